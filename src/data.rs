@@ -1,11 +1,126 @@
-use bytemuck::{Zeroable, Pod};
+use std::collections::HashMap;
 
-#[repr(C)]
-#[derive(Default, Clone, Copy, Debug, Pod, Zeroable)]
-pub struct Vertex {
-    pub position: [f32; 3],
-    pub tex_coords: [f32; 2],
-    pub normal: [f32; 3],
+use bevy::render::{
+    mesh::{Mesh, MeshVertexAttribute, MeshVertexAttributeId},
+    render_resource::VertexFormat,
+};
+use vulkano::format::Format;
+
+pub const ATTRIBUTE_UV_1: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_Uv_1", 274_813_001, VertexFormat::Float32x2);
+
+pub const LOCATION_POSITION: u32 = 0;
+pub const LOCATION_UV_0: u32 = 1;
+pub const LOCATION_NORMAL: u32 = 2;
+pub const LOCATION_COLOR: u32 = 3;
+pub const LOCATION_UV_1: u32 = 4;
+pub const LOCATION_TANGENT: u32 = 5;
+pub const LOCATION_JOINT_INDEX: u32 = 6;
+pub const LOCATION_JOINT_WEIGHT: u32 = 7;
+
+// Inputs of the built-in shader a mesh may lack, fed from a constant per-instance buffer
+pub const DEFAULT_ATTRIBUTES: [(u32, Format, [f32; 4]); 3] = [
+    (LOCATION_UV_0, Format::R32G32_SFLOAT, [0.0; 4]),
+    (LOCATION_NORMAL, Format::R32G32B32_SFLOAT, [0.0; 4]),
+    (LOCATION_COLOR, Format::R32G32B32A32_SFLOAT, [1.0; 4]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VertexAttribute {
+    pub location: u32,
+    pub format: Format,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    attributes: Vec<VertexAttribute>,
+}
+
+pub struct VertexAttributeLocations {
+    locations: HashMap<MeshVertexAttributeId, u32>,
+}
+
+impl VertexLayout {
+    pub fn new(mut attributes: Vec<VertexAttribute>) -> Self {
+        attributes.sort_by_key(|attribute| attribute.location);
+        Self { attributes }
+    }
+
+    pub fn attributes(&self) -> &[VertexAttribute] {
+        &self.attributes
+    }
+
+    pub fn contains(&self, location: u32) -> bool {
+        self.attributes
+            .iter()
+            .any(|attribute| attribute.location == location)
+    }
+}
+
+impl Default for VertexAttributeLocations {
+    fn default() -> Self {
+        let mut locations = Self {
+            locations: HashMap::new(),
+        };
+
+        locations.register(Mesh::ATTRIBUTE_POSITION, LOCATION_POSITION);
+        locations.register(Mesh::ATTRIBUTE_UV_0, LOCATION_UV_0);
+        locations.register(Mesh::ATTRIBUTE_NORMAL, LOCATION_NORMAL);
+        locations.register(Mesh::ATTRIBUTE_COLOR, LOCATION_COLOR);
+        locations.register(ATTRIBUTE_UV_1, LOCATION_UV_1);
+        locations.register(Mesh::ATTRIBUTE_TANGENT, LOCATION_TANGENT);
+        locations.register(Mesh::ATTRIBUTE_JOINT_INDEX, LOCATION_JOINT_INDEX);
+        locations.register(Mesh::ATTRIBUTE_JOINT_WEIGHT, LOCATION_JOINT_WEIGHT);
+
+        locations
+    }
 }
 
-vulkano::impl_vertex!(Vertex, position, tex_coords, normal);
+impl VertexAttributeLocations {
+    pub fn register(&mut self, attribute: impl Into<MeshVertexAttributeId>, location: u32) {
+        self.locations.insert(attribute.into(), location);
+    }
+
+    pub fn get(&self, attribute: impl Into<MeshVertexAttributeId>) -> Option<u32> {
+        self.locations.get(&attribute.into()).copied()
+    }
+}
+
+pub const fn convert_vertex_format(format: VertexFormat) -> Format {
+    match format {
+        VertexFormat::Uint8x2 => Format::R8G8_UINT,
+        VertexFormat::Uint8x4 => Format::R8G8B8A8_UINT,
+        VertexFormat::Sint8x2 => Format::R8G8_SINT,
+        VertexFormat::Sint8x4 => Format::R8G8B8A8_SINT,
+        VertexFormat::Unorm8x2 => Format::R8G8_UNORM,
+        VertexFormat::Unorm8x4 => Format::R8G8B8A8_UNORM,
+        VertexFormat::Snorm8x2 => Format::R8G8_SNORM,
+        VertexFormat::Snorm8x4 => Format::R8G8B8A8_SNORM,
+        VertexFormat::Uint16x2 => Format::R16G16_UINT,
+        VertexFormat::Uint16x4 => Format::R16G16B16A16_UINT,
+        VertexFormat::Sint16x2 => Format::R16G16_SINT,
+        VertexFormat::Sint16x4 => Format::R16G16B16A16_SINT,
+        VertexFormat::Unorm16x2 => Format::R16G16_UNORM,
+        VertexFormat::Unorm16x4 => Format::R16G16B16A16_UNORM,
+        VertexFormat::Snorm16x2 => Format::R16G16_SNORM,
+        VertexFormat::Snorm16x4 => Format::R16G16B16A16_SNORM,
+        VertexFormat::Float16x2 => Format::R16G16_SFLOAT,
+        VertexFormat::Float16x4 => Format::R16G16B16A16_SFLOAT,
+        VertexFormat::Float32 => Format::R32_SFLOAT,
+        VertexFormat::Float32x2 => Format::R32G32_SFLOAT,
+        VertexFormat::Float32x3 => Format::R32G32B32_SFLOAT,
+        VertexFormat::Float32x4 => Format::R32G32B32A32_SFLOAT,
+        VertexFormat::Uint32 => Format::R32_UINT,
+        VertexFormat::Uint32x2 => Format::R32G32_UINT,
+        VertexFormat::Uint32x3 => Format::R32G32B32_UINT,
+        VertexFormat::Uint32x4 => Format::R32G32B32A32_UINT,
+        VertexFormat::Sint32 => Format::R32_SINT,
+        VertexFormat::Sint32x2 => Format::R32G32_SINT,
+        VertexFormat::Sint32x3 => Format::R32G32B32_SINT,
+        VertexFormat::Sint32x4 => Format::R32G32B32A32_SINT,
+        VertexFormat::Float64 => Format::R64_SFLOAT,
+        VertexFormat::Float64x2 => Format::R64G64_SFLOAT,
+        VertexFormat::Float64x3 => Format::R64G64B64_SFLOAT,
+        VertexFormat::Float64x4 => Format::R64G64B64A64_SFLOAT,
+    }
+}
@@ -1,21 +1,28 @@
+use std::path::Path;
+
 use bevy::{
+    asset::AssetServerSettings,
     log::LogPlugin,
     prelude::*,
     scene::ScenePlugin,
     tasks::{IoTaskPool, TaskPool},
     time::TimePlugin,
+    window::WindowId,
 };
-use bevy_obj::ObjPlugin;
 use bevy_rapier3d::{
     plugin::{NoUserData, RapierPhysicsPlugin},
     prelude::{Collider, Friction, RigidBody, Velocity},
 };
 use plugins::{
-    camera::{CameraProjection, FlyCamera, FlyCameraPlugin},
-    renderer::WindowSetting,
+    camera::{CameraProjection, FlyCamera, FlyCameraPlugin, RenderTarget},
+    model::Model,
+    renderer::{OpenWindow, WindowConfig, WindowSetting},
     DefaultRendererPlugins,
 };
-use renderer::material::DisplayMaterial;
+use renderer::{
+    material::{DisplayMaterial, TextureImage},
+    RendererSettings,
+};
 
 pub mod conversion;
 pub mod data;
@@ -28,9 +35,10 @@ fn setup(
     mut commands: Commands,
     asset_server: ResMut<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
+    mut textures: ResMut<Assets<TextureImage>>,
     mut window_setting_events: ResMut<Events<WindowSetting>>,
+    mut open_window_events: ResMut<Events<OpenWindow>>,
 ) {
-    let texture0 = asset_server.load("texture0.png");
     let texture1 = asset_server.load("texture1.png");
 
     commands
@@ -38,10 +46,7 @@ fn setup(
         .insert(meshes.add(Mesh::from(shape::Plane { size: 50.0 })))
         .insert(Transform::from_xyz(0.0, -1.0, 0.0))
         .insert(GlobalTransform::identity())
-        .insert(DisplayMaterial {
-            k_diffuse: Color::WHITE,
-            k_diffuse_map: Some(texture0),
-        })
+        .insert(asset_server.load::<DisplayMaterial, _>("floor.mat.ron"))
         .insert(RigidBody::Fixed)
         .insert(Collider::cuboid(50.0, 0.001, 50.0));
 
@@ -67,6 +72,7 @@ fn setup(
             .insert(DisplayMaterial {
                 k_diffuse: Color::rgb(k_r, k_g, k_b),
                 k_diffuse_map: Some(texture1.clone()),
+                ..default()
             })
             .insert(RigidBody::Dynamic)
             .insert(Velocity::zero())
@@ -77,9 +83,58 @@ fn setup(
     commands
         .spawn()
         .insert(Transform::from_xyz(0.0, 5.0, 5.0))
+        .insert(GlobalTransform::identity())
         .insert(FlyCamera::default())
         .insert(CameraProjection::Perspective(default()));
 
+    let screen = textures.add(TextureImage::render_target(UVec2::new(512, 512)));
+
+    commands
+        .spawn()
+        .insert(Transform::from_xyz(0.0, 30.0, 0.0).looking_at(Vec3::ZERO, Vec3::Z))
+        .insert(GlobalTransform::identity())
+        .insert(CameraProjection::Perspective(default()))
+        .insert(RenderTarget::Texture(screen.clone()));
+
+    commands
+        .spawn()
+        .insert(meshes.add(Mesh::from(shape::Quad::new(Vec2::new(4.0, 4.0)))))
+        .insert(Transform::from_xyz(0.0, 2.0, -10.0))
+        .insert(GlobalTransform::identity())
+        .insert(DisplayMaterial {
+            k_diffuse: Color::WHITE,
+            k_diffuse_map: Some(screen),
+            ..default()
+        });
+
+    let model0: Handle<Model> = asset_server.load("model0.obj");
+
+    commands
+        .spawn()
+        .insert(model0)
+        .insert(Transform::from_xyz(-6.0, 0.0, -6.0))
+        .insert(GlobalTransform::identity());
+
+    // Debug view of the same scene from above
+    let top_down = WindowId::new();
+    open_window_events.send(OpenWindow {
+        id: top_down,
+        config: WindowConfig {
+            title: "top-down".to_owned(),
+            size: Some(Vec2::new(480.0, 480.0)),
+            resizable: true,
+            cursor_grab: false,
+            ..default()
+        },
+    });
+
+    commands
+        .spawn()
+        .insert(Transform::from_xyz(0.0, 40.0, 0.0).looking_at(Vec3::ZERO, Vec3::NEG_Z))
+        .insert(GlobalTransform::identity())
+        .insert(CameraProjection::Perspective(default()))
+        .insert(RenderTarget::Window(top_down));
+
     window_setting_events.send(WindowSetting::SetMouseGrab(true));
 }
 
@@ -87,12 +142,21 @@ fn main() {
     IoTaskPool::init(TaskPool::new);
 
     App::new()
+        .insert_resource(AssetServerSettings {
+            watch_for_changes: true,
+            ..default()
+        })
+        .insert_resource(RendererSettings {
+            shader_dir: cfg!(all(debug_assertions, feature = "glsl"))
+                .then(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("src/shaders")),
+            validation: cfg!(debug_assertions),
+            ..default()
+        })
         .add_plugin(LogPlugin)
         .add_plugin(TimePlugin)
         .add_plugins(DefaultRendererPlugins)
         .add_plugin(ScenePlugin)
         .add_plugin(FlyCameraPlugin)
-        .add_plugin(ObjPlugin)
         .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
         .add_startup_system(setup)
         .run();
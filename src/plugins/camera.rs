@@ -1,16 +1,16 @@
-use std::sync::Arc;
-
 use bevy::{
     math::{Mat4, Vec2, Vec3, Quat},
     prelude::{
-        App, Changed, Commands, Component, CoreStage, Entity, EventReader, Plugin, Query, Res,
-        SystemSet, Transform, With, Time, KeyCode,
+        App, Assets, Changed, Commands, Component, CoreStage, Entity, EventReader, Handle, Plugin,
+        Query, Res, SystemSet, Transform, With, Without, Time, KeyCode,
     },
-    window::{WindowCreated, WindowResized}, input::{Input, mouse::MouseMotion},
+    window::{WindowId, WindowResized, Windows}, input::{Input, mouse::MouseMotion},
 };
-use winit::window::Window;
 
-use crate::projection::{OrthographicProjection, PerspectiveProjection, Projection};
+use crate::{
+    projection::{OrthographicProjection, PerspectiveProjection, Projection},
+    renderer::material::TextureImage,
+};
 
 pub struct CameraPlugin;
 pub struct FlyCameraPlugin;
@@ -26,7 +26,16 @@ pub struct ComputedProjection {
     dimensions: Vec2,
     projection: Mat4,
 }
+#[derive(Component, Clone)]
+pub enum RenderTarget {
+    Window(WindowId),
+    Texture(Handle<TextureImage>),
+}
+
 #[derive(Component)]
+pub struct InactiveCamera;
+
+#[derive(Component, Clone)]
 pub enum CameraProjection {
     Perspective(PerspectiveProjection),
     Orthographic(OrthographicProjection),
@@ -47,7 +56,8 @@ impl Plugin for CameraPlugin {
             CoreStage::PreUpdate,
             SystemSet::new()
                 .with_system(setup_camera_initial)
-                .with_system(update_camera_dimensions),
+                .with_system(update_camera_dimensions)
+                .with_system(update_texture_camera_dimensions),
         );
         app.add_system_to_stage(CoreStage::PostUpdate, update_camera_settings);
     }
@@ -73,41 +83,106 @@ impl ComputedProjection {
     }
 }
 
-fn setup_camera_initial(
-    mut commands: Commands,
-    mut window_create_events: EventReader<WindowCreated>,
-    mut query: Query<(Entity, &CameraProjection)>,
-    window: Res<Arc<Window>>,
-) {
-    let create = window_create_events.iter().last();
-
-    if create.is_some() {
-        let dim = window.inner_size();
-        let dim = Vec2::new(dim.width as f32, dim.height as f32);
+impl Default for RenderTarget {
+    fn default() -> Self {
+        Self::Window(WindowId::primary())
+    }
+}
 
-        for (entity, settings) in query.iter_mut() {
-            let new = ComputedProjection {
-                dimensions: dim,
-                projection: settings.compute_matrix(dim),
-            };
+impl RenderTarget {
+    pub const fn texture(&self) -> Option<&Handle<TextureImage>> {
+        match self {
+            Self::Window(_) => None,
+            Self::Texture(handle) => Some(handle),
+        }
+    }
 
-            commands.entity(entity).insert(new);
+    pub const fn window(&self) -> Option<WindowId> {
+        match self {
+            Self::Window(id) => Some(*id),
+            Self::Texture(_) => None,
         }
     }
+
+    // Cameras without a target draw into the primary window
+    pub fn window_of(target: Option<&Self>) -> Option<WindowId> {
+        target.map_or_else(|| Some(WindowId::primary()), Self::window)
+    }
+}
+
+// Also picks up cameras spawned after the window, e.g. the ones coming from a loaded model.
+// Cameras of a window that isn't open yet are retried on the next frame
+fn setup_camera_initial(
+    mut commands: Commands,
+    query: Query<(Entity, &CameraProjection, Option<&RenderTarget>), Without<ComputedProjection>>,
+    windows: Res<Windows>,
+) {
+    for (entity, settings, target) in query.iter() {
+        let window = match RenderTarget::window_of(target).and_then(|id| windows.get(id)) {
+            Some(window) => window,
+            None => continue,
+        };
+        let dim = Vec2::new(window.width(), window.height());
+
+        let new = ComputedProjection {
+            dimensions: dim,
+            projection: settings.compute_matrix(dim),
+        };
+
+        commands.entity(entity).insert(new);
+    }
 }
 
 fn update_camera_dimensions(
     mut window_resize_events: EventReader<WindowResized>,
-    mut query: Query<(&CameraProjection, &mut ComputedProjection)>,
+    mut query: Query<(
+        &CameraProjection,
+        &mut ComputedProjection,
+        Option<&RenderTarget>,
+    )>,
+) {
+    for resize in window_resize_events.iter() {
+        let dim = Vec2::new(resize.width, resize.height);
+
+        for (settings, mut computed, _) in query
+            .iter_mut()
+            .filter(|(_, _, target)| RenderTarget::window_of(*target) == Some(resize.id))
+        {
+            computed.dimensions = dim;
+            computed.projection = settings.compute_matrix(dim);
+        }
+    }
+}
+
+fn update_texture_camera_dimensions(
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &CameraProjection,
+        &RenderTarget,
+        Option<&mut ComputedProjection>,
+    )>,
+    textures: Res<Assets<TextureImage>>,
 ) {
-    if let Some(resize) = window_resize_events
-        .iter()
-        .last()
-        .map(|e| Vec2::new(e.width, e.height))
-    {
-        for (settings, mut computed) in query.iter_mut() {
-            computed.dimensions = resize;
-            computed.projection = settings.compute_matrix(resize);
+    for (entity, settings, target, computed) in query.iter_mut() {
+        let texture = match target.texture().and_then(|handle| textures.get(handle)) {
+            Some(texture) => texture,
+            None => continue,
+        };
+        let dim = texture.dimensions.as_vec2();
+
+        match computed {
+            Some(mut computed) if computed.dimensions != dim => {
+                computed.dimensions = dim;
+                computed.projection = settings.compute_matrix(dim);
+            }
+            Some(_) => (),
+            None => {
+                commands.entity(entity).insert(ComputedProjection {
+                    dimensions: dim,
+                    projection: settings.compute_matrix(dim),
+                });
+            }
         }
     }
 }
@@ -0,0 +1,41 @@
+use std::marker::PhantomData;
+
+use bevy::prelude::{Added, App, Commands, CoreStage, Entity, Plugin, Query, RemovedComponents};
+
+use crate::renderer::custom::{CustomMaterial, CustomMaterialRegistry, UsesCustomMaterial};
+
+pub struct CustomMaterialPlugin<M: CustomMaterial>(PhantomData<M>);
+
+impl<M: CustomMaterial> Default for CustomMaterialPlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M: CustomMaterial> Plugin for CustomMaterialPlugin<M> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CustomMaterialRegistry>();
+        app.world
+            .resource_mut::<CustomMaterialRegistry>()
+            .register::<M>();
+
+        app.add_system_to_stage(CoreStage::PostUpdate, tag_custom_materials::<M>);
+    }
+}
+
+// Keeps entities with a custom material out of the built-in pass
+fn tag_custom_materials<M: CustomMaterial>(
+    mut commands: Commands,
+    added: Query<Entity, Added<M>>,
+    removed: RemovedComponents<M>,
+) {
+    for entity in added.iter() {
+        commands.entity(entity).insert(UsesCustomMaterial);
+    }
+
+    for entity in removed.iter() {
+        if let Some(mut entity) = commands.get_entity(entity) {
+            entity.remove::<UsesCustomMaterial>();
+        }
+    }
+}
@@ -1,14 +1,29 @@
-use std::sync::Arc;
+use std::collections::HashMap;
 
 use bevy::{
-    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    asset::{AssetLoader, BoxedFuture, HandleId, LoadContext, LoadedAsset},
     math::UVec2,
-    prelude::{AddAsset, Assets, CoreStage, Plugin, ResMut, Res, debug},
+    prelude::{AddAsset, Assets, CoreStage, Plugin, Query, ResMut, Res, debug, error, warn},
 };
-use image::EncodableLayout;
-use vulkano::{format::Format, device::Queue};
+use image::{DynamicImage, ImageError, ImageFormat};
+use vulkano::format::Format;
 
-use crate::renderer::material::TextureImage;
+use crate::renderer::{
+    material::{ColorSpace, DisplayMaterial, TextureImage},
+    upload::{UploadBatch, UploadQueue, UploadTarget},
+};
+
+use self::{
+    compressed::CompressedTextureLoader,
+    gltf::GltfLoader,
+    material::MaterialLoader,
+    obj::{MaterialLibrary, MtlLoader, ObjLibraries, ObjLoader},
+};
+
+mod compressed;
+mod gltf;
+mod material;
+mod obj;
 
 pub struct LoaderPlugin;
 pub struct TextureImageLoader;
@@ -20,31 +35,68 @@ impl AssetLoader for TextureImageLoader {
         load_context: &'a mut LoadContext,
     ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
         Box::pin(async move {
-            let image = image::load_from_memory(bytes)
-                .map_err(bevy::asset::Error::new)?
-                .to_rgba8();
-            let width = image.width();
-            let height = image.height();
-
-            load_context.set_default_asset(LoadedAsset::new(TextureImage::from_bytes(
-                image.as_bytes(),
-                Format::R8G8B8A8_UNORM,
-                UVec2::new(width, height),
-            )));
+            // Some formats (tga) have no magic, so trust the extension first
+            let format = ImageFormat::from_path(load_context.path()).ok();
+            let texture = decode_image(bytes, format).map_err(bevy::asset::Error::new)?;
+
+            load_context.set_default_asset(LoadedAsset::new(texture));
 
             Ok(())
         })
     }
 
     fn extensions(&self) -> &[&str] {
-        &["png"]
+        &["png", "jpg", "jpeg", "tga", "bmp", "hdr", "exr"]
+    }
+}
+
+fn decode_image(bytes: &[u8], format: Option<ImageFormat>) -> Result<TextureImage, ImageError> {
+    let image = match format {
+        Some(format) => image::load_from_memory_with_format(bytes, format),
+        None => image::load_from_memory(bytes),
+    }?;
+    let dimensions = UVec2::new(image.width(), image.height());
+    let (data, format) = convert_image(image);
+
+    Ok(TextureImage::from_bytes(&data, format, dimensions))
+}
+
+fn convert_image(image: DynamicImage) -> (Vec<u8>, Format) {
+    match image {
+        DynamicImage::ImageLuma8(image) => (image.into_raw(), Format::R8_SRGB),
+        DynamicImage::ImageLumaA8(image) => (image.into_raw(), Format::R8G8_SRGB),
+        DynamicImage::ImageLuma16(image) => (
+            bytemuck::cast_slice(&image.into_raw()).to_vec(),
+            Format::R16_UNORM,
+        ),
+        DynamicImage::ImageLumaA16(image) => (
+            bytemuck::cast_slice(&image.into_raw()).to_vec(),
+            Format::R16G16_UNORM,
+        ),
+        image @ (DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_)) => (
+            bytemuck::cast_slice(&image.into_rgba16().into_raw()).to_vec(),
+            Format::R16G16B16A16_UNORM,
+        ),
+        image @ (DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) => (
+            bytemuck::cast_slice(&image.into_rgba32f().into_raw()).to_vec(),
+            Format::R32G32B32A32_SFLOAT,
+        ),
+        image => (image.into_rgba8().into_raw(), Format::R8G8B8A8_SRGB),
     }
 }
 
 impl Plugin for LoaderPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
-        app.add_asset_loader(TextureImageLoader)
-            .add_system_to_stage(CoreStage::PreUpdate, upload_textures);
+        app.add_asset::<MaterialLibrary>()
+            .add_asset::<ObjLibraries>()
+            .add_asset_loader(TextureImageLoader)
+            .add_asset_loader(CompressedTextureLoader)
+            .add_asset_loader(GltfLoader)
+            .add_asset_loader(ObjLoader)
+            .add_asset_loader(MtlLoader)
+            .add_asset_loader(MaterialLoader)
+            .add_system_to_stage(CoreStage::PreUpdate, upload_textures)
+            .add_system_to_stage(CoreStage::PreUpdate, obj::reload_obj_models);
     }
 
     fn name(&self) -> &str {
@@ -52,11 +104,77 @@ impl Plugin for LoaderPlugin {
     }
 }
 
-fn upload_textures(mut textures: ResMut<Assets<TextureImage>>, queue: Res<Arc<Queue>>) {
-    for (handle, texture) in textures.iter_mut() {
-        if texture.image.is_none() {
+fn slot_color_spaces<'a>(
+    materials: impl Iterator<Item = &'a DisplayMaterial>,
+) -> HashMap<HandleId, ColorSpace> {
+    let mut color_spaces = HashMap::new();
+
+    for (handle, color_space) in materials.flat_map(DisplayMaterial::texture_slots) {
+        match color_spaces.insert(handle.id, color_space) {
+            Some(previous) if previous != color_space => {
+                warn!(
+                    "Texture {:?} is used both as {:?} and {:?} data, using {:?}",
+                    handle, previous, color_space, color_space
+                );
+            }
+            _ => (),
+        }
+    }
+
+    color_spaces
+}
+
+fn upload_textures(
+    mut textures: ResMut<Assets<TextureImage>>,
+    materials: Query<&DisplayMaterial>,
+    material_assets: Res<Assets<DisplayMaterial>>,
+    mut upload_queue: ResMut<UploadQueue>,
+) {
+    // Reloaded textures arrive as fresh assets without an image and are picked up here.
+    // Going through get_mut only for these keeps Modified events meaningful.
+    let pending = textures
+        .iter()
+        .filter(|(_, texture)| texture.needs_upload())
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+
+    if pending.is_empty() {
+        return;
+    }
+
+    let color_spaces = slot_color_spaces(
+        materials
+            .iter()
+            .chain(material_assets.iter().map(|(_, material)| material)),
+    );
+
+    let mut batch = UploadBatch::default();
+    for handle in pending {
+        if let Some(texture) = textures.get_mut(handle) {
+            if let Some(&color_space) = color_spaces.get(&handle) {
+                texture.set_color_space(color_space);
+            }
+
             debug!("Uploading texture {:?} to GPU\n", handle);
-            texture.upload_to_gpu(queue.clone());
+            match texture.upload(upload_queue.queue().clone()) {
+                Ok((view, future)) => {
+                    let ticket = upload_queue.next_ticket();
+                    texture.pending_upload = Some(ticket);
+                    batch.add(
+                        future,
+                        UploadTarget::Texture {
+                            id: handle,
+                            ticket,
+                            view,
+                        },
+                    );
+                }
+                Err(err) => {
+                    error!("Could not upload texture {:?}: {}", handle, err);
+                    texture.upload_error = Some(err);
+                }
+            }
         }
     }
+    upload_queue.submit(batch);
 }
@@ -0,0 +1,255 @@
+use std::io::Cursor;
+
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    math::UVec2,
+};
+use ddsfile::{Caps2, D3DFormat, Dds, DxgiFormat, MiscFlag};
+use vulkano::format::Format;
+
+use crate::renderer::material::{level_size, TextureError, TextureImage};
+
+pub struct CompressedTextureLoader;
+
+#[derive(Debug)]
+pub enum CompressedTextureError {
+    UnsupportedFormat(String),
+    Supercompressed,
+    VolumeTexture,
+    CubeMap,
+    ArrayTexture(u32),
+}
+
+struct Levels {
+    data: Vec<u8>,
+    format: Format,
+    dimensions: UVec2,
+    mip_levels: u32,
+}
+
+impl AssetLoader for CompressedTextureLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let extension = load_context
+                .path()
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            let levels = if extension == "dds" {
+                load_dds(bytes)?
+            } else {
+                load_ktx2(bytes)?
+            };
+
+            load_context.set_default_asset(LoadedAsset::new(TextureImage::from_levels(
+                &levels.data,
+                levels.format,
+                levels.dimensions,
+                levels.mip_levels,
+            )));
+
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ktx2", "dds"]
+    }
+}
+
+impl std::fmt::Display for CompressedTextureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedFormat(format) => write!(f, "Unsupported texture format: {}", format),
+            Self::Supercompressed => write!(f, "Supercompressed KTX2 files are not supported"),
+            Self::VolumeTexture => write!(f, "3D textures are not supported"),
+            Self::CubeMap => write!(f, "Cube map textures are not supported"),
+            Self::ArrayTexture(layers) => {
+                write!(f, "Array textures are not supported ({} layers)", layers)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompressedTextureError {}
+
+fn mip_level_size(format: Format, dimensions: UVec2, level: u32) -> usize {
+    let width = (dimensions.x >> level).max(1);
+    let height = (dimensions.y >> level).max(1);
+    level_size(format, width, height)
+}
+
+fn load_ktx2(bytes: &[u8]) -> Result<Levels, bevy::asset::Error> {
+    let reader = ktx2::Reader::new(bytes).map_err(bevy::asset::Error::new)?;
+    let header = reader.header();
+
+    if header.supercompression_scheme.is_some() {
+        return Err(CompressedTextureError::Supercompressed.into());
+    }
+    // Materials only sample 2D textures, layered files would need array views and samplers
+    if header.pixel_depth > 1 {
+        return Err(CompressedTextureError::VolumeTexture.into());
+    }
+    if header.face_count > 1 {
+        return Err(CompressedTextureError::CubeMap.into());
+    }
+    if header.layer_count > 1 {
+        return Err(CompressedTextureError::ArrayTexture(header.layer_count).into());
+    }
+
+    let format = header
+        .format
+        .and_then(convert_ktx2_format)
+        .ok_or_else(|| CompressedTextureError::UnsupportedFormat(format!("{:?}", header.format)))?;
+    let dimensions = UVec2::new(header.pixel_width, header.pixel_height.max(1));
+    let mip_levels = header.level_count.max(1);
+
+    let mut data = Vec::new();
+    for (level, bytes) in reader.levels().enumerate() {
+        if bytes.len() < mip_level_size(format, dimensions, level as u32) {
+            return Err(TextureError::TruncatedData.into());
+        }
+
+        data.extend_from_slice(bytes);
+    }
+
+    Ok(Levels {
+        data,
+        format,
+        dimensions,
+        mip_levels,
+    })
+}
+
+fn load_dds(bytes: &[u8]) -> Result<Levels, bevy::asset::Error> {
+    let dds = Dds::read(Cursor::new(bytes)).map_err(bevy::asset::Error::new)?;
+
+    let cube = dds.header.caps2.contains(Caps2::CUBEMAP)
+        || dds.header10.as_ref().map_or(false, |header| {
+            header.misc_flag.contains(MiscFlag::TEXTURECUBE)
+        });
+    // Same as for KTX2, only plain 2D textures
+    if dds.get_depth() > 1 {
+        return Err(CompressedTextureError::VolumeTexture.into());
+    }
+    if cube {
+        return Err(CompressedTextureError::CubeMap.into());
+    }
+    if dds.get_num_array_layers() > 1 {
+        return Err(CompressedTextureError::ArrayTexture(dds.get_num_array_layers()).into());
+    }
+
+    let format = match (dds.get_dxgi_format(), dds.get_d3d_format()) {
+        (Some(format), _) => convert_dxgi_format(format),
+        (None, Some(format)) => convert_d3d_format(format),
+        (None, None) => None,
+    }
+    .ok_or_else(|| {
+        CompressedTextureError::UnsupportedFormat(format!(
+            "{:?} / {:?}",
+            dds.get_dxgi_format(),
+            dds.get_d3d_format()
+        ))
+    })?;
+    let dimensions = UVec2::new(dds.get_width(), dds.get_height());
+    let mip_levels = dds.get_num_mipmap_levels().max(1);
+
+    // With a single layer the mip chain is already stored level by level
+    let size: usize = (0..mip_levels)
+        .map(|level| mip_level_size(format, dimensions, level))
+        .sum();
+    let data = dds
+        .data
+        .get(..size)
+        .ok_or(TextureError::TruncatedData)?
+        .to_vec();
+
+    Ok(Levels {
+        data,
+        format,
+        dimensions,
+        mip_levels,
+    })
+}
+
+fn convert_ktx2_format(format: ktx2::Format) -> Option<Format> {
+    let table = [
+        (ktx2::Format::R8_UNORM, Format::R8_UNORM),
+        (ktx2::Format::R8_SRGB, Format::R8_SRGB),
+        (ktx2::Format::R8G8_UNORM, Format::R8G8_UNORM),
+        (ktx2::Format::R8G8_SRGB, Format::R8G8_SRGB),
+        (ktx2::Format::R8G8B8A8_UNORM, Format::R8G8B8A8_UNORM),
+        (ktx2::Format::R8G8B8A8_SRGB, Format::R8G8B8A8_SRGB),
+        (ktx2::Format::B8G8R8A8_UNORM, Format::B8G8R8A8_UNORM),
+        (ktx2::Format::B8G8R8A8_SRGB, Format::B8G8R8A8_SRGB),
+        (ktx2::Format::R16G16B16A16_UNORM, Format::R16G16B16A16_UNORM),
+        (ktx2::Format::R16G16B16A16_SFLOAT, Format::R16G16B16A16_SFLOAT),
+        (ktx2::Format::R32G32B32A32_SFLOAT, Format::R32G32B32A32_SFLOAT),
+        (ktx2::Format::BC1_RGB_UNORM_BLOCK, Format::BC1_RGB_UNORM_BLOCK),
+        (ktx2::Format::BC1_RGB_SRGB_BLOCK, Format::BC1_RGB_SRGB_BLOCK),
+        (ktx2::Format::BC1_RGBA_UNORM_BLOCK, Format::BC1_RGBA_UNORM_BLOCK),
+        (ktx2::Format::BC1_RGBA_SRGB_BLOCK, Format::BC1_RGBA_SRGB_BLOCK),
+        (ktx2::Format::BC2_UNORM_BLOCK, Format::BC2_UNORM_BLOCK),
+        (ktx2::Format::BC2_SRGB_BLOCK, Format::BC2_SRGB_BLOCK),
+        (ktx2::Format::BC3_UNORM_BLOCK, Format::BC3_UNORM_BLOCK),
+        (ktx2::Format::BC3_SRGB_BLOCK, Format::BC3_SRGB_BLOCK),
+        (ktx2::Format::BC4_UNORM_BLOCK, Format::BC4_UNORM_BLOCK),
+        (ktx2::Format::BC4_SNORM_BLOCK, Format::BC4_SNORM_BLOCK),
+        (ktx2::Format::BC5_UNORM_BLOCK, Format::BC5_UNORM_BLOCK),
+        (ktx2::Format::BC5_SNORM_BLOCK, Format::BC5_SNORM_BLOCK),
+        (ktx2::Format::BC6H_UFLOAT_BLOCK, Format::BC6H_UFLOAT_BLOCK),
+        (ktx2::Format::BC6H_SFLOAT_BLOCK, Format::BC6H_SFLOAT_BLOCK),
+        (ktx2::Format::BC7_UNORM_BLOCK, Format::BC7_UNORM_BLOCK),
+        (ktx2::Format::BC7_SRGB_BLOCK, Format::BC7_SRGB_BLOCK),
+    ];
+
+    table
+        .iter()
+        .find(|(ktx2_format, _)| *ktx2_format == format)
+        .map(|&(_, format)| format)
+}
+
+const fn convert_dxgi_format(format: DxgiFormat) -> Option<Format> {
+    Some(match format {
+        DxgiFormat::R8_UNorm => Format::R8_UNORM,
+        DxgiFormat::R8G8_UNorm => Format::R8G8_UNORM,
+        DxgiFormat::R8G8B8A8_UNorm => Format::R8G8B8A8_UNORM,
+        DxgiFormat::R8G8B8A8_UNorm_sRGB => Format::R8G8B8A8_SRGB,
+        DxgiFormat::B8G8R8A8_UNorm => Format::B8G8R8A8_UNORM,
+        DxgiFormat::B8G8R8A8_UNorm_sRGB => Format::B8G8R8A8_SRGB,
+        DxgiFormat::R16G16B16A16_UNorm => Format::R16G16B16A16_UNORM,
+        DxgiFormat::R16G16B16A16_Float => Format::R16G16B16A16_SFLOAT,
+        DxgiFormat::R32G32B32A32_Float => Format::R32G32B32A32_SFLOAT,
+        DxgiFormat::BC1_UNorm => Format::BC1_RGBA_UNORM_BLOCK,
+        DxgiFormat::BC1_UNorm_sRGB => Format::BC1_RGBA_SRGB_BLOCK,
+        DxgiFormat::BC2_UNorm => Format::BC2_UNORM_BLOCK,
+        DxgiFormat::BC2_UNorm_sRGB => Format::BC2_SRGB_BLOCK,
+        DxgiFormat::BC3_UNorm => Format::BC3_UNORM_BLOCK,
+        DxgiFormat::BC3_UNorm_sRGB => Format::BC3_SRGB_BLOCK,
+        DxgiFormat::BC4_UNorm => Format::BC4_UNORM_BLOCK,
+        DxgiFormat::BC4_SNorm => Format::BC4_SNORM_BLOCK,
+        DxgiFormat::BC5_UNorm => Format::BC5_UNORM_BLOCK,
+        DxgiFormat::BC5_SNorm => Format::BC5_SNORM_BLOCK,
+        DxgiFormat::BC6H_UF16 => Format::BC6H_UFLOAT_BLOCK,
+        DxgiFormat::BC6H_SF16 => Format::BC6H_SFLOAT_BLOCK,
+        DxgiFormat::BC7_UNorm => Format::BC7_UNORM_BLOCK,
+        DxgiFormat::BC7_UNorm_sRGB => Format::BC7_SRGB_BLOCK,
+        _ => return None,
+    })
+}
+
+const fn convert_d3d_format(format: D3DFormat) -> Option<Format> {
+    Some(match format {
+        D3DFormat::DXT1 => Format::BC1_RGBA_UNORM_BLOCK,
+        D3DFormat::DXT3 => Format::BC2_UNORM_BLOCK,
+        D3DFormat::DXT5 => Format::BC3_UNORM_BLOCK,
+        D3DFormat::A8R8G8B8 => Format::B8G8R8A8_UNORM,
+        D3DFormat::A8B8G8R8 => Format::R8G8B8A8_UNORM,
+        _ => return None,
+    })
+}
@@ -0,0 +1,394 @@
+use std::path::{Path, PathBuf};
+
+use bevy::{
+    asset::{AssetLoader, AssetPath, BoxedFuture, LoadContext, LoadedAsset},
+    math::{Quat, Vec3},
+    prelude::{warn, Color, Handle, Mesh, Transform},
+    render::mesh::{Indices, PrimitiveTopology},
+};
+use gltf::{
+    buffer::Source as BufferSource,
+    camera::Projection,
+    image::Source as ImageSource,
+    mesh::Mode,
+    texture::{MagFilter, MinFilter, WrappingMode},
+    Gltf,
+};
+use percent_encoding::percent_decode_str;
+use vulkano::sampler::{Filter, SamplerAddressMode, SamplerMipmapMode};
+
+use crate::{
+    data::ATTRIBUTE_UV_1,
+    plugins::{
+        camera::CameraProjection,
+        model::{Model, ModelNode, ModelPrimitive},
+    },
+    projection::{OrthographicProjection, PerspectiveProjection},
+    renderer::material::{AlphaMode, DisplayMaterial, SamplerSettings, TextureImage},
+};
+
+use super::decode_image;
+
+pub struct GltfLoader;
+
+#[derive(Debug)]
+pub enum GltfError {
+    MissingBlob,
+    InvalidDataUri,
+    ViewOutOfBounds(usize),
+}
+
+struct ImportedTexture {
+    handle: Handle<TextureImage>,
+    // Set for external images, which are loaded through the asset server
+    dependency: Option<AssetPath<'static>>,
+}
+
+impl AssetLoader for GltfLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let gltf = Gltf::from_slice(bytes)?;
+            let buffers = load_buffers(&gltf, load_context).await?;
+
+            let textures = gltf
+                .images()
+                .map(|image| load_image(&image, &buffers, load_context))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let materials = gltf
+                .materials()
+                .enumerate()
+                .map(|(index, material)| {
+                    load_context.set_labeled_asset(
+                        &format!("Material{}", index),
+                        load_material(&material, &textures),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let default_material = load_context
+                .set_labeled_asset("MaterialDefault", LoadedAsset::new(DisplayMaterial::default()));
+
+            let meshes = gltf
+                .meshes()
+                .map(|mesh| {
+                    mesh.primitives()
+                        .map(|primitive| {
+                            let label =
+                                format!("Mesh{}/Primitive{}", mesh.index(), primitive.index());
+                            load_primitive(&primitive, &buffers).map(|loaded| {
+                                load_context.set_labeled_asset(&label, LoadedAsset::new(loaded))
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+
+            let nodes = gltf
+                .nodes()
+                .map(|node| load_node(&node, &meshes, &materials, &default_material))
+                .collect::<Vec<_>>();
+
+            for scene in gltf.scenes() {
+                let model = Model {
+                    nodes: nodes.clone(),
+                    roots: scene.nodes().map(|node| node.index()).collect(),
+                };
+                load_context
+                    .set_labeled_asset(&format!("Scene{}", scene.index()), LoadedAsset::new(model));
+            }
+
+            let roots = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+                Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+                None => nodes
+                    .iter()
+                    .enumerate()
+                    .filter(|&(index, _)| nodes.iter().all(|node| !node.children.contains(&index)))
+                    .map(|(index, _)| index)
+                    .collect(),
+            };
+            load_context.set_default_asset(LoadedAsset::new(Model { nodes, roots }));
+
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["gltf", "glb"]
+    }
+}
+
+impl std::fmt::Display for GltfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingBlob => write!(f, "Buffer refers to a missing GLB binary chunk"),
+            Self::InvalidDataUri => write!(f, "Only base64 data URIs are supported"),
+            Self::ViewOutOfBounds(view) => write!(f, "Buffer view {} exceeds its buffer", view),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+fn relative_path(load_context: &LoadContext, uri: &str) -> PathBuf {
+    let uri = percent_decode_str(uri).decode_utf8_lossy();
+    load_context
+        .path()
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(uri.as_ref())
+}
+
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>, bevy::asset::Error> {
+    let (_, data) = uri
+        .strip_prefix("data:")
+        .and_then(|uri| uri.split_once(";base64,"))
+        .ok_or(GltfError::InvalidDataUri)?;
+
+    Ok(base64::decode(data)?)
+}
+
+async fn load_buffers(
+    gltf: &Gltf,
+    load_context: &LoadContext<'_>,
+) -> Result<Vec<Vec<u8>>, bevy::asset::Error> {
+    let mut buffers = Vec::new();
+
+    for buffer in gltf.buffers() {
+        let data = match buffer.source() {
+            BufferSource::Bin => gltf.blob.clone().ok_or(GltfError::MissingBlob)?,
+            BufferSource::Uri(uri) if uri.starts_with("data:") => decode_data_uri(uri)?,
+            BufferSource::Uri(uri) => {
+                load_context
+                    .read_asset_bytes(relative_path(load_context, uri))
+                    .await?
+            }
+        };
+
+        buffers.push(data);
+    }
+
+    Ok(buffers)
+}
+
+fn load_image(
+    image: &gltf::Image,
+    buffers: &[Vec<u8>],
+    load_context: &mut LoadContext,
+) -> Result<ImportedTexture, bevy::asset::Error> {
+    let bytes = match image.source() {
+        ImageSource::View { view, .. } => buffers
+            .get(view.buffer().index())
+            .and_then(|buffer| buffer.get(view.offset()..view.offset() + view.length()))
+            .ok_or(GltfError::ViewOutOfBounds(view.index()))?
+            .to_vec(),
+        ImageSource::Uri { uri, .. } if uri.starts_with("data:") => decode_data_uri(uri)?,
+        ImageSource::Uri { uri, .. } => {
+            // Let the asset server pick the loader, so external files also hot reload
+            let path = AssetPath::new(relative_path(load_context, uri), None);
+            return Ok(ImportedTexture {
+                handle: load_context.get_handle(path.clone()),
+                dependency: Some(path),
+            });
+        }
+    };
+
+    let texture = decode_image(&bytes, None)?;
+    Ok(ImportedTexture {
+        handle: load_context
+            .set_labeled_asset(&format!("Texture{}", image.index()), LoadedAsset::new(texture)),
+        dependency: None,
+    })
+}
+
+fn load_material(
+    material: &gltf::Material,
+    textures: &[ImportedTexture],
+) -> LoadedAsset<DisplayMaterial> {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, a] = pbr.base_color_factor();
+
+    let mut display_material = DisplayMaterial {
+        k_diffuse: Color::rgba_linear(r, g, b, a),
+        alpha_mode: match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+            // 0.5 is the cutoff glTF specifies when there is none
+            gltf::material::AlphaMode::Mask => {
+                AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5))
+            }
+            gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+        },
+        ..Default::default()
+    };
+    let mut dependency = None;
+
+    if let Some(info) = pbr.base_color_texture() {
+        if info.tex_coord() != 0 {
+            warn!(
+                "Material {:?} samples its base color from UV set {}, only set 0 is supported",
+                material.name(),
+                info.tex_coord()
+            );
+        }
+
+        let texture = &textures[info.texture().source().index()];
+        display_material.k_diffuse_map = Some(texture.handle.clone());
+        display_material.k_diffuse_sampler = Some(convert_sampler(&info.texture().sampler()));
+        dependency = texture.dependency.clone();
+    }
+
+    let asset = LoadedAsset::new(display_material);
+    match dependency {
+        Some(path) => asset.with_dependency(path),
+        None => asset,
+    }
+}
+
+fn load_primitive(primitive: &gltf::Primitive, buffers: &[Vec<u8>]) -> Option<Mesh> {
+    let topology = match primitive.mode() {
+        Mode::Points => PrimitiveTopology::PointList,
+        Mode::Lines => PrimitiveTopology::LineList,
+        Mode::LineStrip => PrimitiveTopology::LineStrip,
+        Mode::Triangles => PrimitiveTopology::TriangleList,
+        Mode::TriangleStrip => PrimitiveTopology::TriangleStrip,
+        mode => {
+            warn!("Skipping primitive with unsupported mode {:?}", mode);
+            return None;
+        }
+    };
+
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+    let mut mesh = Mesh::new(topology);
+
+    if let Some(positions) = reader.read_positions() {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions.collect::<Vec<_>>());
+    }
+    if let Some(normals) = reader.read_normals() {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals.collect::<Vec<_>>());
+    }
+    if let Some(uvs) = reader.read_tex_coords(0) {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs.into_f32().collect::<Vec<_>>());
+    }
+    if let Some(uvs) = reader.read_tex_coords(1) {
+        mesh.insert_attribute(ATTRIBUTE_UV_1, uvs.into_f32().collect::<Vec<_>>());
+    }
+    if let Some(colors) = reader.read_colors(0) {
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_COLOR,
+            colors.into_rgba_f32().collect::<Vec<_>>(),
+        );
+    }
+    if let Some(tangents) = reader.read_tangents() {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, tangents.collect::<Vec<_>>());
+    }
+    if let Some(indices) = reader.read_indices() {
+        mesh.set_indices(Some(Indices::U32(indices.into_u32().collect())));
+    }
+
+    Some(mesh)
+}
+
+fn load_node(
+    node: &gltf::Node,
+    meshes: &[Vec<Option<Handle<Mesh>>>],
+    materials: &[Handle<DisplayMaterial>],
+    default_material: &Handle<DisplayMaterial>,
+) -> ModelNode {
+    let (translation, rotation, scale) = node.transform().decomposed();
+
+    let primitives = node
+        .mesh()
+        .into_iter()
+        .flat_map(|mesh| mesh.primitives().map(move |primitive| (mesh.index(), primitive)))
+        .filter_map(|(mesh, primitive)| {
+            let material = primitive
+                .material()
+                .index()
+                .map_or(default_material, |index| &materials[index]);
+
+            meshes[mesh][primitive.index()]
+                .clone()
+                .map(|mesh| ModelPrimitive {
+                    mesh,
+                    material: material.clone(),
+                })
+        })
+        .collect();
+
+    ModelNode {
+        name: node.name().map(str::to_owned),
+        transform: Transform {
+            translation: Vec3::from(translation),
+            rotation: Quat::from_array(rotation),
+            scale: Vec3::from(scale),
+        },
+        primitives,
+        camera: node.camera().map(|camera| convert_camera(&camera)),
+        children: node.children().map(|child| child.index()).collect(),
+    }
+}
+
+fn convert_camera(camera: &gltf::Camera) -> CameraProjection {
+    match camera.projection() {
+        Projection::Perspective(perspective) => {
+            CameraProjection::Perspective(PerspectiveProjection {
+                fov: perspective.yfov(),
+                near: perspective.znear(),
+                // Infinite projections aren't supported
+                far: perspective
+                    .zfar()
+                    .unwrap_or_else(|| PerspectiveProjection::default().far),
+            })
+        }
+        Projection::Orthographic(orthographic) => {
+            CameraProjection::Orthographic(OrthographicProjection {
+                near: orthographic.znear(),
+                far: orthographic.zfar(),
+                left: -orthographic.xmag(),
+                right: orthographic.xmag(),
+                bottom: -orthographic.ymag(),
+                top: orthographic.ymag(),
+            })
+        }
+    }
+}
+
+fn convert_sampler(sampler: &gltf::texture::Sampler) -> SamplerSettings {
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => Filter::Nearest,
+        Some(MagFilter::Linear) | None => Filter::Linear,
+    };
+    let (min_filter, mipmap_mode) = match sampler.min_filter() {
+        Some(MinFilter::Nearest | MinFilter::NearestMipmapNearest) => {
+            (Filter::Nearest, SamplerMipmapMode::Nearest)
+        }
+        Some(MinFilter::NearestMipmapLinear) => (Filter::Nearest, SamplerMipmapMode::Linear),
+        Some(MinFilter::LinearMipmapNearest) => (Filter::Linear, SamplerMipmapMode::Nearest),
+        Some(MinFilter::Linear | MinFilter::LinearMipmapLinear) | None => {
+            (Filter::Linear, SamplerMipmapMode::Linear)
+        }
+    };
+
+    SamplerSettings {
+        mag_filter,
+        min_filter,
+        mipmap_mode,
+        address_mode: [
+            convert_wrapping(sampler.wrap_s()),
+            convert_wrapping(sampler.wrap_t()),
+            SamplerAddressMode::Repeat,
+        ],
+        ..SamplerSettings::default()
+    }
+}
+
+const fn convert_wrapping(mode: WrappingMode) -> SamplerAddressMode {
+    match mode {
+        WrappingMode::ClampToEdge => SamplerAddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => SamplerAddressMode::MirroredRepeat,
+        WrappingMode::Repeat => SamplerAddressMode::Repeat,
+    }
+}
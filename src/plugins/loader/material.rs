@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use bevy::{
+    asset::{AssetLoader, AssetPath, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::{Color, Handle},
+};
+use serde::Deserialize;
+use vulkano::sampler::{Filter, SamplerAddressMode, SamplerMipmapMode};
+
+use crate::renderer::material::{AlphaMode, DisplayMaterial, SamplerSettings, TextureImage};
+
+pub struct MaterialLoader;
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct MaterialDescriptor {
+    diffuse: [f32; 4],
+    diffuse_map: Option<String>,
+    diffuse_sampler: Option<SamplerDescriptor>,
+    specular: [f32; 3],
+    shininess: f32,
+    alpha_mode: AlphaMode,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct SamplerDescriptor {
+    mag_filter: FilterDescriptor,
+    min_filter: FilterDescriptor,
+    mipmap_mode: FilterDescriptor,
+    address_mode: AddressModeDescriptor,
+    anisotropy: Option<f32>,
+    lod_bias: f32,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+enum FilterDescriptor {
+    Nearest,
+    Linear,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+enum AddressModeDescriptor {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+impl AssetLoader for MaterialLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let descriptor = ron::de::from_bytes::<MaterialDescriptor>(bytes)?;
+            let [r, g, b, a] = descriptor.diffuse;
+            let [s_r, s_g, s_b] = descriptor.specular;
+
+            let mut material = DisplayMaterial {
+                k_diffuse: Color::rgba(r, g, b, a),
+                k_diffuse_sampler: descriptor.diffuse_sampler.map(SamplerDescriptor::convert),
+                k_specular: Color::rgb(s_r, s_g, s_b),
+                shininess: descriptor.shininess,
+                alpha_mode: descriptor.alpha_mode,
+                ..Default::default()
+            };
+
+            // Texture paths are relative to the material file
+            let mut dependencies = Vec::new();
+            if let Some(path) = &descriptor.diffuse_map {
+                let path = AssetPath::new(
+                    load_context
+                        .path()
+                        .parent()
+                        .unwrap_or_else(|| Path::new(""))
+                        .join(path),
+                    None,
+                );
+                let handle: Handle<TextureImage> = load_context.get_handle(path.clone());
+
+                material.k_diffuse_map = Some(handle);
+                dependencies.push(path);
+            }
+
+            load_context
+                .set_default_asset(LoadedAsset::new(material).with_dependencies(dependencies));
+
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["mat.ron"]
+    }
+}
+
+impl Default for MaterialDescriptor {
+    fn default() -> Self {
+        Self {
+            diffuse: [1.0; 4],
+            diffuse_map: None,
+            diffuse_sampler: None,
+            specular: [0.0; 3],
+            shininess: 32.0,
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+}
+
+impl Default for SamplerDescriptor {
+    fn default() -> Self {
+        Self {
+            mag_filter: FilterDescriptor::Linear,
+            min_filter: FilterDescriptor::Linear,
+            mipmap_mode: FilterDescriptor::Linear,
+            address_mode: AddressModeDescriptor::Repeat,
+            anisotropy: Some(16.0),
+            lod_bias: 0.0,
+        }
+    }
+}
+
+impl SamplerDescriptor {
+    fn convert(self) -> SamplerSettings {
+        let filter = |filter: FilterDescriptor| match filter {
+            FilterDescriptor::Nearest => Filter::Nearest,
+            FilterDescriptor::Linear => Filter::Linear,
+        };
+        let address_mode = match self.address_mode {
+            AddressModeDescriptor::Repeat => SamplerAddressMode::Repeat,
+            AddressModeDescriptor::MirroredRepeat => SamplerAddressMode::MirroredRepeat,
+            AddressModeDescriptor::ClampToEdge => SamplerAddressMode::ClampToEdge,
+        };
+
+        SamplerSettings {
+            mag_filter: filter(self.mag_filter),
+            min_filter: filter(self.min_filter),
+            mipmap_mode: match self.mipmap_mode {
+                FilterDescriptor::Nearest => SamplerMipmapMode::Nearest,
+                FilterDescriptor::Linear => SamplerMipmapMode::Linear,
+            },
+            address_mode: [address_mode; 3],
+            anisotropy: self.anisotropy,
+            lod_bias: self.lod_bias,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    asset::{
+        AssetEvent, AssetLoader, AssetPath, AssetServer, BoxedFuture, LoadContext, LoadedAsset,
+    },
+    prelude::{warn, Assets, Color, EventReader, Handle, Mesh, Res, Transform},
+    reflect::TypeUuid,
+    render::mesh::{Indices, PrimitiveTopology},
+};
+use tobj::LoadError;
+
+use crate::{
+    plugins::model::{Model, ModelNode, ModelPrimitive},
+    renderer::material::{AlphaMode, DisplayMaterial, TextureImage},
+};
+
+pub struct ObjLoader;
+pub struct MtlLoader;
+
+// Loaded only so the asset server watches .mtl files, their contents end up in the models
+#[derive(TypeUuid)]
+#[uuid = "14078f00-ecf2-43a4-8fd3-19e11f8d916a"]
+pub struct MaterialLibrary;
+
+// The libraries a .obj file was loaded with
+#[derive(TypeUuid)]
+#[uuid = "69c02228-d809-4e8b-a201-64155bead489"]
+pub struct ObjLibraries(Vec<AssetPath<'static>>);
+
+impl AssetLoader for ObjLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            // tobj wants material libraries synchronously, so fetch them up front
+            let mut libraries = HashMap::new();
+            let mut library_paths = Vec::new();
+            for library in material_libraries(bytes) {
+                let path = relative_path(load_context, &library);
+                match load_context.read_asset_bytes(&path).await {
+                    Ok(data) => {
+                        libraries.insert(PathBuf::from(library), data);
+                    }
+                    Err(err) => warn!("Could not read material library {}: {}", library, err),
+                }
+                library_paths.push(AssetPath::new(path, None));
+            }
+
+            let (models, materials) = tobj::load_obj_buf(
+                &mut Cursor::new(bytes),
+                &tobj::GPU_LOAD_OPTIONS,
+                |path| {
+                    let data = libraries.get(path).ok_or(LoadError::OpenFileFailed)?;
+                    tobj::load_mtl_buf(&mut Cursor::new(data))
+                },
+            )?;
+
+            let materials = materials.unwrap_or_else(|err| {
+                warn!(
+                    "Could not load materials for {:?}: {}",
+                    load_context.path(),
+                    err
+                );
+                Vec::new()
+            });
+            let materials = materials
+                .iter()
+                .enumerate()
+                .map(|(index, material)| {
+                    let loaded = load_material(material, load_context);
+                    load_context.set_labeled_asset(&format!("Material{}", index), loaded)
+                })
+                .collect::<Vec<_>>();
+            let default_material = load_context
+                .set_labeled_asset("MaterialDefault", LoadedAsset::new(DisplayMaterial::default()));
+
+            // tobj already splits objects at every usemtl, so each model is a single primitive
+            let nodes = models
+                .into_iter()
+                .enumerate()
+                .map(|(index, model)| {
+                    let material = model
+                        .mesh
+                        .material_id
+                        .and_then(|id| materials.get(id))
+                        .unwrap_or(&default_material)
+                        .clone();
+                    let mesh = load_context.set_labeled_asset(
+                        &format!("Mesh{}", index),
+                        LoadedAsset::new(convert_mesh(&model.mesh)),
+                    );
+
+                    ModelNode {
+                        name: Some(model.name),
+                        transform: Transform::identity(),
+                        primitives: vec![ModelPrimitive { mesh, material }],
+                        camera: None,
+                        children: Vec::new(),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            load_context.set_labeled_asset(
+                "Libraries",
+                LoadedAsset::new(ObjLibraries(library_paths.clone())),
+            );
+            load_context.set_default_asset(
+                LoadedAsset::new(Model {
+                    roots: (0..nodes.len()).collect(),
+                    nodes,
+                })
+                .with_dependencies(library_paths),
+            );
+
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["obj"]
+    }
+}
+
+impl AssetLoader for MtlLoader {
+    fn load<'a>(
+        &'a self,
+        _bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            load_context.set_default_asset(LoadedAsset::new(MaterialLibrary));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["mtl"]
+    }
+}
+
+pub fn reload_obj_models(
+    mut library_events: EventReader<AssetEvent<MaterialLibrary>>,
+    obj_libraries: Res<Assets<ObjLibraries>>,
+    asset_server: Res<AssetServer>,
+) {
+    for event in library_events.iter() {
+        let library = match event {
+            AssetEvent::Modified { handle } => match asset_server.get_handle_path(handle) {
+                Some(path) => path.to_owned(),
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        for (id, ObjLibraries(paths)) in obj_libraries.iter() {
+            if !paths.contains(&library) {
+                continue;
+            }
+
+            if let Some(obj) = asset_server.get_handle_path(id) {
+                asset_server.reload_asset(obj.path());
+            }
+        }
+    }
+}
+
+fn material_libraries(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("mtllib"), Some(library)) => Some(library.to_owned()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn relative_path(load_context: &LoadContext, path: &str) -> PathBuf {
+    load_context
+        .path()
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(path)
+}
+
+fn load_material(
+    material: &tobj::Material,
+    load_context: &LoadContext,
+) -> LoadedAsset<DisplayMaterial> {
+    let [r, g, b] = material.diffuse;
+    let [s_r, s_g, s_b] = material.specular;
+
+    let mut display_material = DisplayMaterial {
+        k_diffuse: Color::rgba_linear(r, g, b, material.dissolve),
+        k_specular: Color::rgb_linear(s_r, s_g, s_b),
+        shininess: material.shininess,
+        alpha_mode: if material.dissolve < 1.0 {
+            AlphaMode::Blend
+        } else {
+            AlphaMode::Opaque
+        },
+        ..Default::default()
+    };
+
+    if material.diffuse_texture.is_empty() {
+        return LoadedAsset::new(display_material);
+    }
+
+    let path = AssetPath::new(relative_path(load_context, &material.diffuse_texture), None);
+    let handle: Handle<TextureImage> = load_context.get_handle(path.clone());
+    display_material.k_diffuse_map = Some(handle);
+
+    LoadedAsset::new(display_material).with_dependency(path)
+}
+
+fn convert_mesh(mesh: &tobj::Mesh) -> Mesh {
+    let mut result = Mesh::new(PrimitiveTopology::TriangleList);
+
+    let positions = mesh
+        .positions
+        .chunks_exact(3)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect::<Vec<_>>();
+    let vertex_count = positions.len();
+    result.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+
+    if mesh.normals.len() == vertex_count * 3 {
+        let normals = mesh
+            .normals
+            .chunks_exact(3)
+            .map(|n| [n[0], n[1], n[2]])
+            .collect::<Vec<_>>();
+        result.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    }
+    // OBJ puts the texture origin at the bottom left
+    if mesh.texcoords.len() == vertex_count * 2 {
+        let uvs = mesh
+            .texcoords
+            .chunks_exact(2)
+            .map(|t| [t[0], 1.0 - t[1]])
+            .collect::<Vec<_>>();
+        result.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    }
+    if mesh.vertex_color.len() == vertex_count * 3 {
+        let colors = mesh
+            .vertex_color
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2], 1.0])
+            .collect::<Vec<_>>();
+        result.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    }
+
+    result.set_indices(Some(Indices::U32(mesh.indices.clone())));
+    result
+}
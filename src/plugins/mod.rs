@@ -1,15 +1,17 @@
 use bevy::{
-    app::PluginGroupBuilder, asset::AssetPlugin, input::InputPlugin, prelude::PluginGroup,
-    window::WindowPlugin,
+    app::PluginGroupBuilder, asset::AssetPlugin, hierarchy::HierarchyPlugin, input::InputPlugin,
+    prelude::PluginGroup, transform::TransformPlugin, window::WindowPlugin,
 };
 
 pub use self::renderer::RendererPlugin;
-use self::{camera::CameraPlugin, loader::LoaderPlugin};
+use self::{camera::CameraPlugin, loader::LoaderPlugin, model::ModelPlugin};
 
 pub struct DefaultRendererPlugins;
 
 pub mod camera;
+pub mod custom;
 pub mod loader;
+pub mod model;
 pub mod renderer;
 
 impl PluginGroup for DefaultRendererPlugins {
@@ -18,9 +20,12 @@ impl PluginGroup for DefaultRendererPlugins {
         group.add(AssetPlugin);
         group.add(InputPlugin);
         group.add(WindowPlugin);
+        group.add(TransformPlugin);
+        group.add(HierarchyPlugin);
 
         group.add(LoaderPlugin);
         group.add(RendererPlugin);
         group.add(CameraPlugin);
+        group.add(ModelPlugin);
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+
+use bevy::{
+    asset::AssetEvent,
+    prelude::{
+        AddAsset, App, Assets, BuildChildren, ChildBuilder, Commands, Component, CoreStage,
+        DespawnRecursiveExt, Entity, EventReader, GlobalTransform, Handle, Mesh, Name, Plugin,
+        Query, Res, Transform,
+    },
+    reflect::TypeUuid,
+};
+
+use crate::{
+    plugins::camera::{CameraProjection, InactiveCamera},
+    renderer::material::DisplayMaterial,
+};
+
+pub struct ModelPlugin;
+
+#[derive(TypeUuid)]
+#[uuid = "7a3f2b0e-5d41-4c8e-9b6a-1f0d2c3e4a58"]
+pub struct Model {
+    pub nodes: Vec<ModelNode>,
+    pub roots: Vec<usize>,
+}
+
+#[derive(Clone)]
+pub struct ModelNode {
+    pub name: Option<String>,
+    pub transform: Transform,
+    pub primitives: Vec<ModelPrimitive>,
+    pub camera: Option<CameraProjection>,
+    pub children: Vec<usize>,
+}
+
+#[derive(Clone)]
+pub struct ModelPrimitive {
+    pub mesh: Handle<Mesh>,
+    pub material: Handle<DisplayMaterial>,
+}
+
+#[derive(Component)]
+pub struct SpawnedModel;
+
+impl Plugin for ModelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Model>()
+            .add_system_to_stage(CoreStage::PreUpdate, spawn_models);
+    }
+
+    fn name(&self) -> &str {
+        "alnyan-model"
+    }
+}
+
+fn spawn_node(parent: &mut ChildBuilder, model: &Model, index: usize) {
+    let node = &model.nodes[index];
+    let mut entity = parent.spawn();
+
+    entity
+        .insert(node.transform)
+        .insert(GlobalTransform::identity());
+    if let Some(name) = &node.name {
+        entity.insert(Name::new(name.clone()));
+    }
+    // Cameras from a model shouldn't take over the window until asked to
+    if let Some(projection) = &node.camera {
+        entity.insert(projection.clone()).insert(InactiveCamera);
+    }
+
+    entity.with_children(|parent| {
+        for primitive in &node.primitives {
+            let mut child = parent.spawn();
+
+            child
+                .insert(primitive.mesh.clone())
+                .insert(primitive.material.clone())
+                .insert(Transform::identity())
+                .insert(GlobalTransform::identity());
+        }
+
+        for &child in &node.children {
+            spawn_node(parent, model, child);
+        }
+    });
+}
+
+fn spawn_models(
+    mut commands: Commands,
+    mut model_events: EventReader<AssetEvent<Model>>,
+    query: Query<(Entity, &Handle<Model>, Option<&SpawnedModel>)>,
+    models: Res<Assets<Model>>,
+) {
+    let modified = model_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { handle } => Some(handle.id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    for (entity, handle, spawned) in query.iter() {
+        if spawned.is_some() && !modified.contains(&handle.id) {
+            continue;
+        }
+
+        let model = match models.get(handle) {
+            Some(model) => model,
+            None => continue,
+        };
+
+        let mut entity = commands.entity(entity);
+        if spawned.is_some() {
+            entity.despawn_descendants();
+        }
+
+        entity.insert(SpawnedModel).with_children(|parent| {
+            for &root in &model.roots {
+                spawn_node(parent, model, root);
+            }
+        });
+    }
+}
@@ -1,146 +1,734 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use bevy::{
+    ecs::event::ManualEventReader,
     input::{keyboard::KeyboardInput, mouse::MouseMotion},
-    math::Vec2,
+    math::{DVec2, IVec2, UVec2, Vec2},
     prelude::{
-        debug, AddAsset, App, Assets, Changed, Commands, CoreStage, Entity, EventReader, Events,
-        Handle, Mesh, Or, Plugin, Query, Res, SystemSet, Without,
+        debug, error, warn, AddAsset, App, AssetEvent, Assets, ChangeTrackers, Commands, CoreStage,
+        Entity, EventReader, EventWriter, Events, Handle, Mesh, Mut,
+        ParallelSystemDescriptorCoercion, Plugin, Query, Res, ResMut, SystemSet, World,
+    },
+    window::{
+        CursorEntered, CursorLeft, CursorMoved, PresentMode, WindowBackendScaleFactorChanged,
+        WindowCloseRequested, WindowClosed, WindowCommand, WindowCreated, WindowDescriptor,
+        WindowFocused, WindowId, WindowMode, WindowMoved, WindowResized, WindowScaleFactorChanged,
+        Windows,
     },
-    window::{WindowCreated, WindowId, WindowResized}, render::mesh::VertexAttributeValues,
 };
-use vulkano::device::Queue;
+use raw_window_handle::HasRawWindowHandle;
+use vulkano::swapchain::PresentMode as SwapchainPresentMode;
 use winit::{
+    dpi::{LogicalPosition, LogicalSize, PhysicalPosition, PhysicalSize},
+    error::OsError,
     event::{DeviceEvent, WindowEvent},
-    event_loop::{ControlFlow, EventLoop},
-    window::{Window, WindowBuilder},
+    event_loop::{ControlFlow, EventLoop, EventLoopWindowTarget},
+    monitor::{MonitorHandle, VideoMode},
+    window::{Fullscreen, Icon, Window, WindowBuilder},
 };
 
 use crate::{
     conversion::{convert_element_state, convert_virtual_keycode},
-    data::Vertex,
+    data::VertexAttributeLocations,
     renderer::{
+        error::RendererError,
         material::{DisplayMaterial, TextureImage},
-        mesh::DisplayMesh,
-        VulkanContext,
+        mesh::{prepare_mesh, DisplayMesh, InvalidMesh, MeshUploadError},
+        upload::{PendingMesh, UploadBatch, UploadQueue, UploadTarget},
+        RendererSettings, VulkanContext, WindowHandle,
     },
 };
 
 pub struct RendererPlugin;
 
 pub enum WindowSetting {
-    SetFullscreen(bool),
+    // None goes back to a regular window
+    SetFullscreen(Option<FullscreenMode>),
     SetMouseGrab(bool),
+    SetTitle(String),
+    SetSize(Vec2),
+}
+
+// Opens another OS window sharing the renderer, cameras draw into it through
+// RenderTarget::Window(id). Closing the primary window still exits the app
+pub struct OpenWindow {
+    pub id: WindowId,
+    pub config: WindowConfig,
+}
+
+// Read once when the window is created, sizes and positions are logical
+#[derive(Clone)]
+pub struct WindowConfig {
+    pub title: String,
+    pub size: Option<Vec2>,
+    pub position: Option<Vec2>,
+    pub resizable: bool,
+    pub decorations: bool,
+    pub icon: Option<PathBuf>,
+    pub vsync: bool,
+    pub cursor_grab: bool,
+    pub fullscreen: Option<FullscreenMode>,
+}
+
+#[derive(Clone, Debug)]
+pub enum FullscreenMode {
+    Borderless(MonitorSelection),
+    Exclusive(MonitorSelection, VideoModeSelection),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum MonitorSelection {
+    Current,
+    Primary,
+    Index(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum VideoModeSelection {
+    // Highest resolution, then refresh rate, then bit depth
+    Best,
+    Exact {
+        size: UVec2,
+        refresh_rate: Option<u16>,
+    },
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            title: env!("CARGO_PKG_NAME").to_owned(),
+            size: None,
+            position: None,
+            resizable: false,
+            decorations: true,
+            icon: None,
+            vsync: true,
+            cursor_grab: true,
+            fullscreen: None,
+        }
+    }
 }
 
 #[allow(clippy::type_complexity)]
 fn update_meshes(
     mut commands: Commands,
-    query: Query<(Entity, &Handle<Mesh>), Or<(Changed<Handle<Mesh>>, Without<DisplayMesh>)>>,
+    query: Query<(
+        Entity,
+        &Handle<Mesh>,
+        ChangeTrackers<Handle<Mesh>>,
+        Option<&DisplayMesh>,
+        Option<&PendingMesh>,
+        Option<&InvalidMesh>,
+    )>,
     meshes: Res<Assets<Mesh>>,
-    queue: Res<Arc<Queue>>,
+    locations: Res<VertexAttributeLocations>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    mut mesh_errors: EventWriter<MeshUploadError>,
+    mut renderer_errors: EventWriter<RendererError>,
+    mut upload_queue: ResMut<UploadQueue>,
 ) {
-    for (entity, mesh) in query.iter() {
-        if let Some(mesh) = meshes.get(mesh) {
+    let modified = mesh_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { handle } => Some(handle.id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let mut batch = UploadBatch::default();
+
+    for (entity, handle, tracker, display_mesh, pending, invalid) in query.iter() {
+        if !tracker.is_changed()
+            && !modified.contains(&handle.id)
+            && (display_mesh.is_some() || pending.is_some() || invalid.is_some())
+        {
+            continue;
+        }
+
+        if let Some(mesh) = meshes.get(handle) {
             debug!("Uploading a mesh for {:?}", entity);
 
-            let positions = mesh
-                .attribute(Mesh::ATTRIBUTE_POSITION)
-                .unwrap()
-                .as_float3()
-                .unwrap();
-            let tex_coords = mesh.attribute(Mesh::ATTRIBUTE_UV_0).unwrap();
-            let tex_coords = match tex_coords {
-                VertexAttributeValues::Float32x2(v) => v,
-                _ => panic!("Texture coordinates are not in float x2 format")
+            let prepared = match prepare_mesh(mesh, &locations) {
+                Ok(prepared) => prepared,
+                Err(error) => {
+                    warn!("Mesh {:?} of {:?} cannot be rendered: {}", handle, entity, error);
+                    mesh_errors.send(MeshUploadError {
+                        entity,
+                        mesh: handle.clone(),
+                        error,
+                    });
+                    commands.entity(entity).insert(InvalidMesh);
+                    continue;
+                }
             };
-            let normals = mesh
-                .attribute(Mesh::ATTRIBUTE_NORMAL)
-                .unwrap()
-                .as_float3()
-                .unwrap();
-            assert_eq!(positions.len(), normals.len());
-
-            let vertices =
-                itertools::izip!(positions, tex_coords, normals).map(|(&p, &t, &n)| Vertex {
-                    position: p,
-                    tex_coords: t,
-                    normal: n,
-                });
-            let indices: Vec<u32> = mesh.indices().unwrap().iter().map(|p| p as u32).collect();
 
-            let dmesh = DisplayMesh::new(vertices, indices, queue.clone());
+            let (mesh, future) = match DisplayMesh::upload(prepared, upload_queue.queue().clone()) {
+                Ok(uploaded) => uploaded,
+                Err(err) => {
+                    error!("Could not upload mesh {:?} of {:?}: {}", handle, entity, err);
+                    renderer_errors.send(err);
+                    commands.entity(entity).insert(InvalidMesh);
+                    continue;
+                }
+            };
+            let ticket = upload_queue.next_ticket();
 
-            commands.entity(entity).insert(dmesh);
+            batch.add(
+                future,
+                UploadTarget::Mesh {
+                    entity,
+                    ticket,
+                    mesh,
+                },
+            );
+            commands
+                .entity(entity)
+                .insert(PendingMesh(ticket))
+                .remove::<InvalidMesh>();
         }
     }
+
+    upload_queue.submit(batch);
 }
 
-fn update_window(window: Res<Arc<Window>>, mut window_setting_events: EventReader<WindowSetting>) {
+// Uploads may have been superseded while in flight, only the latest ticket is attached
+fn finish_uploads(
+    mut commands: Commands,
+    mut upload_queue: ResMut<UploadQueue>,
+    mut textures: ResMut<Assets<TextureImage>>,
+    pending_meshes: Query<&PendingMesh>,
+    mut renderer_errors: EventWriter<RendererError>,
+) {
+    for (err, targets) in upload_queue.take_failed() {
+        for target in targets {
+            match target {
+                UploadTarget::Texture { id, ticket, .. } => {
+                    if let Some(texture) = textures
+                        .get_mut(id)
+                        .filter(|texture| texture.pending_upload == Some(ticket))
+                    {
+                        texture.pending_upload = None;
+                        texture.upload_error = Some(RendererError::from(err.clone()).into());
+                    }
+                }
+                UploadTarget::Mesh { entity, ticket, .. } => {
+                    let current = pending_meshes
+                        .get(entity)
+                        .map_or(false, |pending| pending.0 == ticket);
+
+                    if current {
+                        commands
+                            .entity(entity)
+                            .insert(InvalidMesh)
+                            .remove::<PendingMesh>();
+                    }
+                }
+            }
+        }
+
+        renderer_errors.send(err.into());
+    }
+
+    for target in upload_queue.take_completed() {
+        match target {
+            UploadTarget::Texture { id, ticket, view } => {
+                if let Some(texture) = textures
+                    .get_mut(id)
+                    .filter(|texture| texture.pending_upload == Some(ticket))
+                {
+                    texture.image = Some(view);
+                    texture.pending_upload = None;
+                }
+            }
+            UploadTarget::Mesh {
+                entity,
+                ticket,
+                mesh,
+            } => {
+                let current = pending_meshes
+                    .get(entity)
+                    .map_or(false, |pending| pending.0 == ticket);
+
+                if current {
+                    commands
+                        .entity(entity)
+                        .insert(mesh)
+                        .remove::<PendingMesh>();
+                }
+            }
+        }
+    }
+}
+
+// Goes through the bevy window so it stays in sync, the runner applies its commands
+fn update_window(
+    window: Res<Arc<Window>>,
+    mut windows: ResMut<Windows>,
+    mut window_setting_events: EventReader<WindowSetting>,
+) {
+    let bevy_window = match windows.get_primary_mut() {
+        Some(bevy_window) => bevy_window,
+        None => return,
+    };
+
     for event in window_setting_events.iter() {
         match event {
-            WindowSetting::SetMouseGrab(true) => {
-                window.set_cursor_visible(false);
-                window.set_cursor_grab(true).unwrap();
+            WindowSetting::SetMouseGrab(grab) => {
+                bevy_window.set_cursor_lock_mode(*grab);
+                bevy_window.set_cursor_visibility(!*grab);
+            }
+            // Window modes can't carry the monitor and video mode, so winit is told directly
+            WindowSetting::SetFullscreen(mode) => {
+                let switched = match mode {
+                    Some(mode) => set_fullscreen(&window, mode),
+                    None => {
+                        window.set_fullscreen(None);
+                        true
+                    }
+                };
+                if switched {
+                    bevy_window.set_mode(window_mode(mode.as_ref()));
+                }
+            }
+            WindowSetting::SetTitle(title) => bevy_window.set_title(title.clone()),
+            WindowSetting::SetSize(size) => bevy_window.set_resolution(size.x, size.y),
+        }
+    }
+}
+
+fn select_monitor(window: &Window, selection: MonitorSelection) -> Option<MonitorHandle> {
+    match selection {
+        MonitorSelection::Current => window.current_monitor(),
+        MonitorSelection::Primary => window.primary_monitor(),
+        MonitorSelection::Index(index) => window.available_monitors().nth(index),
+    }
+}
+
+fn select_video_mode(monitor: &MonitorHandle, selection: VideoModeSelection) -> Option<VideoMode> {
+    match selection {
+        VideoModeSelection::Best => monitor.video_modes().max_by_key(|mode| {
+            let size = mode.size();
+            (size.width * size.height, mode.refresh_rate(), mode.bit_depth())
+        }),
+        VideoModeSelection::Exact { size, refresh_rate } => monitor
+            .video_modes()
+            .filter(|mode| mode.size() == PhysicalSize::new(size.x, size.y))
+            .filter(|mode| refresh_rate.map_or(true, |rate| mode.refresh_rate() == rate))
+            .max_by_key(|mode| (mode.refresh_rate(), mode.bit_depth())),
+    }
+}
+
+// The swapchain follows through the resize event or an out of date surface. Returns false
+// when the window stays as it was
+fn set_fullscreen(window: &Window, mode: &FullscreenMode) -> bool {
+    let fullscreen = match *mode {
+        FullscreenMode::Borderless(monitor) => {
+            Fullscreen::Borderless(select_monitor(window, monitor))
+        }
+        FullscreenMode::Exclusive(monitor, video_mode) => {
+            let monitor = match select_monitor(window, monitor) {
+                Some(monitor) => monitor,
+                None => {
+                    warn!("No monitor matches {:?}, staying windowed", monitor);
+                    return false;
+                }
+            };
+
+            match select_video_mode(&monitor, video_mode) {
+                Some(video_mode) => Fullscreen::Exclusive(video_mode),
+                None => {
+                    warn!(
+                        "{} has no video mode matching {:?}, available: {:?}",
+                        monitor.name().unwrap_or_default(),
+                        video_mode,
+                        monitor
+                            .video_modes()
+                            .map(|mode| mode.to_string())
+                            .collect::<Vec<_>>()
+                    );
+                    return false;
+                }
+            }
+        }
+    };
+
+    window.set_fullscreen(Some(fullscreen));
+    true
+}
+
+fn load_icon(path: &Path) -> Option<Icon> {
+    let image = match image::open(path) {
+        Ok(image) => image.into_rgba8(),
+        Err(err) => {
+            warn!("Could not load window icon {:?}: {}", path, err);
+            return None;
+        }
+    };
+    let (width, height) = image.dimensions();
+
+    Icon::from_rgba(image.into_raw(), width, height)
+        .map_err(|err| warn!("Invalid window icon {:?}: {}", path, err))
+        .ok()
+}
+
+fn window_mode(fullscreen: Option<&FullscreenMode>) -> WindowMode {
+    match fullscreen {
+        None => WindowMode::Windowed,
+        Some(FullscreenMode::Borderless(_)) => WindowMode::BorderlessFullscreen,
+        Some(FullscreenMode::Exclusive(_, VideoModeSelection::Best)) => WindowMode::Fullscreen,
+        Some(FullscreenMode::Exclusive(_, VideoModeSelection::Exact { .. })) => {
+            WindowMode::SizedFullscreen
+        }
+    }
+}
+
+fn present_mode(mode: SwapchainPresentMode) -> PresentMode {
+    match mode {
+        SwapchainPresentMode::Immediate => PresentMode::Immediate,
+        SwapchainPresentMode::Mailbox => PresentMode::Mailbox,
+        _ => PresentMode::Fifo,
+    }
+}
+
+// Mirrors the winit window for plugins that read bevy's Windows resource
+fn create_bevy_window(
+    id: WindowId,
+    window: &Window,
+    config: &WindowConfig,
+    present_mode: PresentMode,
+) -> bevy::window::Window {
+    let scale_factor = window.scale_factor();
+    let size = window.inner_size();
+    let logical_size = size.to_logical::<f32>(scale_factor);
+    let position = window
+        .outer_position()
+        .ok()
+        .map(|position| IVec2::new(position.x, position.y));
+
+    let descriptor = WindowDescriptor {
+        width: logical_size.width,
+        height: logical_size.height,
+        title: config.title.clone(),
+        present_mode,
+        resizable: config.resizable,
+        decorations: config.decorations,
+        cursor_visible: !config.cursor_grab,
+        cursor_locked: config.cursor_grab,
+        // The requested monitor or video mode may not have been there
+        mode: if window.fullscreen().is_some() {
+            window_mode(config.fullscreen.as_ref())
+        } else {
+            WindowMode::Windowed
+        },
+        ..WindowDescriptor::default()
+    };
+
+    bevy::window::Window::new(
+        id,
+        &descriptor,
+        size.width,
+        size.height,
+        scale_factor,
+        position,
+        window.raw_window_handle(),
+    )
+}
+
+// Keeps the Windows entry up to date and sends the events bevy_winit would
+fn forward_window_event(world: &mut World, id: WindowId, event: &WindowEvent) {
+    world.resource_scope(|world, mut windows: Mut<Windows>| {
+        let window = match windows.get_mut(id) {
+            Some(window) => window,
+            None => return,
+        };
+
+        match event {
+            WindowEvent::CloseRequested => world.send_event(WindowCloseRequested { id }),
+            WindowEvent::Resized(size) => {
+                window.update_actual_size_from_backend(size.width, size.height);
+                world.send_event(WindowResized {
+                    id,
+                    width: window.width(),
+                    height: window.height(),
+                });
             }
-            WindowSetting::SetMouseGrab(false) => {
-                window.set_cursor_visible(true);
-                window.set_cursor_grab(false).unwrap();
+            WindowEvent::Moved(position) => {
+                let position = IVec2::new(position.x, position.y);
+                window.update_actual_position_from_backend(position);
+                world.send_event(WindowMoved { id, position });
+            }
+            WindowEvent::Focused(focused) => {
+                window.update_focused_status_from_backend(*focused);
+                world.send_event(WindowFocused {
+                    id,
+                    focused: *focused,
+                });
+            }
+            WindowEvent::ScaleFactorChanged {
+                scale_factor,
+                new_inner_size,
+            } => {
+                window.update_scale_factor_from_backend(*scale_factor);
+                window.update_actual_size_from_backend(new_inner_size.width, new_inner_size.height);
+                world.send_event(WindowBackendScaleFactorChanged {
+                    id,
+                    scale_factor: *scale_factor,
+                });
+                if window.scale_factor_override().is_none() {
+                    world.send_event(WindowScaleFactorChanged {
+                        id,
+                        scale_factor: *scale_factor,
+                    });
+                }
+            }
+            WindowEvent::CursorEntered { .. } => world.send_event(CursorEntered { id }),
+            WindowEvent::CursorLeft { .. } => {
+                window.update_cursor_physical_position_from_backend(None);
+                world.send_event(CursorLeft { id });
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                // Bevy puts the origin at the bottom left corner
+                let physical_position =
+                    DVec2::new(position.x, window.physical_height() as f64 - position.y);
+                window.update_cursor_physical_position_from_backend(Some(physical_position));
+                world.send_event(CursorMoved {
+                    id,
+                    position: (physical_position / window.scale_factor()).as_vec2(),
+                });
             }
             _ => (),
         }
+    });
+}
+
+// Already the case after a WindowSetting::SetFullscreen, switching again would lose its
+// monitor and video mode
+fn in_window_mode(window: &Window, mode: WindowMode) -> bool {
+    match window.fullscreen() {
+        None => matches!(mode, WindowMode::Windowed),
+        Some(Fullscreen::Borderless(_)) => matches!(mode, WindowMode::BorderlessFullscreen),
+        Some(Fullscreen::Exclusive(_)) => {
+            matches!(mode, WindowMode::Fullscreen | WindowMode::SizedFullscreen)
+        }
     }
 }
 
+// Returns true once the window was asked to close
+fn apply_window_command(window: &Window, command: WindowCommand) -> bool {
+    match command {
+        WindowCommand::SetWindowMode { mode, .. } if in_window_mode(window, mode) => (),
+        WindowCommand::SetWindowMode { mode, resolution } => {
+            let fullscreen = match mode {
+                WindowMode::Windowed => None,
+                WindowMode::BorderlessFullscreen => {
+                    Some(FullscreenMode::Borderless(MonitorSelection::Current))
+                }
+                WindowMode::Fullscreen => Some(FullscreenMode::Exclusive(
+                    MonitorSelection::Current,
+                    VideoModeSelection::Best,
+                )),
+                WindowMode::SizedFullscreen => Some(FullscreenMode::Exclusive(
+                    MonitorSelection::Current,
+                    VideoModeSelection::Exact {
+                        size: resolution,
+                        refresh_rate: None,
+                    },
+                )),
+            };
+
+            match fullscreen {
+                Some(fullscreen) => {
+                    set_fullscreen(window, &fullscreen);
+                }
+                None => window.set_fullscreen(None),
+            }
+        }
+        WindowCommand::SetTitle { title } => window.set_title(&title),
+        WindowCommand::SetResolution {
+            logical_resolution, ..
+        } => window.set_inner_size(LogicalSize::new(logical_resolution.x, logical_resolution.y)),
+        WindowCommand::SetResizable { resizable } => window.set_resizable(resizable),
+        WindowCommand::SetDecorations { decorations } => window.set_decorations(decorations),
+        WindowCommand::SetCursorLockMode { locked } => {
+            if let Err(err) = window.set_cursor_grab(locked) {
+                warn!("Could not change cursor grab: {}", err);
+            }
+        }
+        WindowCommand::SetCursorVisibility { visible } => window.set_cursor_visible(visible),
+        WindowCommand::SetCursorPosition { position } => {
+            let height = window
+                .inner_size()
+                .to_logical::<f32>(window.scale_factor())
+                .height;
+            let position = LogicalPosition::new(position.x, height - position.y);
+            if let Err(err) = window.set_cursor_position(position) {
+                warn!("Could not move the cursor: {}", err);
+            }
+        }
+        WindowCommand::SetMaximized { maximized } => window.set_maximized(maximized),
+        WindowCommand::SetMinimized { minimized } => window.set_minimized(minimized),
+        WindowCommand::SetPosition { position } => {
+            window.set_outer_position(PhysicalPosition::new(position.x, position.y));
+        }
+        WindowCommand::Close => return true,
+        command => debug!("Ignoring window command {:?}", command),
+    }
+
+    false
+}
+
+fn build_window(
+    config: &WindowConfig,
+    target: &EventLoopWindowTarget<()>,
+) -> Result<WindowHandle, OsError> {
+    let mut builder = WindowBuilder::new()
+        .with_title(&config.title)
+        .with_resizable(config.resizable)
+        .with_decorations(config.decorations)
+        .with_window_icon(config.icon.as_deref().and_then(load_icon));
+    if let Some(size) = config.size {
+        builder = builder.with_inner_size(LogicalSize::new(size.x, size.y));
+    }
+    if let Some(position) = config.position {
+        builder = builder.with_position(LogicalPosition::new(position.x, position.y));
+    }
+
+    let window = Arc::new(builder.build(target)?);
+    if let Some(mode) = &config.fullscreen {
+        set_fullscreen(&window, mode);
+    }
+
+    Ok(window)
+}
+
+fn grab_cursor(window: &Window, grab: bool) {
+    if let Err(err) = window.set_cursor_grab(grab) {
+        warn!("Could not change cursor grab: {}", err);
+    }
+    window.set_cursor_visible(!grab);
+}
+
+fn open_window(
+    world: &mut World,
+    renderer: &mut VulkanContext,
+    target: &EventLoopWindowTarget<()>,
+    id: WindowId,
+    config: &WindowConfig,
+) -> Option<WindowHandle> {
+    let window = match build_window(config, target) {
+        Ok(window) => window,
+        Err(err) => {
+            error!("Could not create window {:?}: {}", id, err);
+            return None;
+        }
+    };
+
+    if let Err(err) = renderer.add_window(id, window.clone(), config.vsync) {
+        error!("Could not render to window {:?}: {}", id, err);
+        world.send_event(err);
+        return None;
+    }
+
+    if config.cursor_grab {
+        grab_cursor(&window, true);
+    }
+
+    let present_mode = renderer
+        .present_mode(id)
+        .map_or(PresentMode::Fifo, present_mode);
+    world
+        .resource_mut::<Windows>()
+        .add(create_bevy_window(id, &window, config, present_mode));
+    world.send_event(WindowCreated { id });
+
+    Some(window)
+}
+
 fn renderer_runner(mut app: App) {
     debug!("Running");
+    let config = app
+        .world
+        .get_resource::<WindowConfig>()
+        .cloned()
+        .unwrap_or_default();
+
     let event_loop = EventLoop::new();
-    let window = Arc::new(
-        WindowBuilder::new()
-            .with_title("Test")
-            .with_resizable(false)
-            .build(&event_loop)
-            .unwrap(),
-    );
+    let window = match build_window(&config, &event_loop) {
+        Ok(window) => window,
+        Err(err) => {
+            error!("Could not create window: {}", err);
+            return;
+        }
+    };
 
-    let mut renderer = VulkanContext::new_windowed(window.clone());
+    let settings = app
+        .world
+        .get_resource::<RendererSettings>()
+        .cloned()
+        .unwrap_or_default();
+    let mut renderer = match VulkanContext::new_windowed(window.clone(), &settings, config.vsync) {
+        Ok(renderer) => renderer,
+        Err(err) => {
+            error!("Could not initialize the renderer: {}", err);
+            return;
+        }
+    };
 
-    // TODO somehow interate with "Windows" resource
+    let primary_id = WindowId::primary();
+    let present_mode = renderer
+        .present_mode(primary_id)
+        .map_or(PresentMode::Fifo, present_mode);
+    app.world.resource_mut::<Windows>().add(create_bevy_window(
+        primary_id,
+        &window,
+        &config,
+        present_mode,
+    ));
     app.insert_resource(window.clone())
-        .insert_resource(renderer.gfx_queue().clone());
+        .insert_resource(renderer.gfx_queue().clone())
+        .insert_resource(UploadQueue::new(renderer.upload_queue().clone()));
 
-    app.world.send_event(WindowCreated {
-        id: WindowId::default(),
-    });
+    app.world.send_event(WindowCreated { id: primary_id });
 
     app.update();
 
-    window.set_cursor_grab(true).unwrap();
-    window.set_cursor_visible(false);
+    if config.cursor_grab {
+        grab_cursor(&window, true);
+    }
+
+    let mut os_windows = HashMap::from([(window.id(), (primary_id, window))]);
+    let mut open_window_events = ManualEventReader::<OpenWindow>::default();
 
-    event_loop.run(move |event, _, flow| match event {
-        winit::event::Event::WindowEvent { event, .. } => match event {
-            WindowEvent::CloseRequested => *flow = ControlFlow::Exit,
-            WindowEvent::KeyboardInput { input, .. } => {
-                let mut keyboard_input_events = app.world.resource_mut::<Events<KeyboardInput>>();
+    event_loop.run(move |event, target, flow| match event {
+        winit::event::Event::WindowEvent { window_id, event } => {
+            let id = match os_windows.get(&window_id) {
+                Some((id, _)) => *id,
+                None => return,
+            };
 
-                keyboard_input_events.send(KeyboardInput {
-                    scan_code: input.scancode,
-                    key_code: input.virtual_keycode.map(convert_virtual_keycode),
-                    state: convert_element_state(input.state),
-                });
-            }
-            WindowEvent::Resized(size) => {
-                let mut window_resized_events = app.world.resource_mut::<Events<WindowResized>>();
-                renderer.invalidate_surface();
-                window_resized_events.send(WindowResized {
-                    width: size.width as f32,
-                    height: size.height as f32,
-                    id: WindowId::default(),
-                });
+            // Closing goes through WindowCloseRequested, so the app gets a say in it
+            forward_window_event(&mut app.world, id, &event);
+
+            match event {
+                WindowEvent::KeyboardInput { input, .. } => {
+                    let mut keyboard_input_events =
+                        app.world.resource_mut::<Events<KeyboardInput>>();
+
+                    keyboard_input_events.send(KeyboardInput {
+                        scan_code: input.scancode,
+                        key_code: input.virtual_keycode.map(convert_virtual_keycode),
+                        state: convert_element_state(input.state),
+                    });
+                }
+                WindowEvent::Resized(_) | WindowEvent::ScaleFactorChanged { .. } => {
+                    renderer.invalidate_surface(id);
+                }
+                _ => *flow = ControlFlow::Poll,
             }
-            _ => *flow = ControlFlow::Poll,
-        },
+        }
         winit::event::Event::DeviceEvent {
             event: DeviceEvent::MouseMotion { delta: (x, y) },
             ..
@@ -151,12 +739,49 @@ fn renderer_runner(mut app: App) {
         }
         winit::event::Event::MainEventsCleared => {
             app.update();
+
+            let requests = open_window_events
+                .iter(app.world.resource::<Events<OpenWindow>>())
+                .map(|event| (event.id, event.config.clone()))
+                .collect::<Vec<_>>();
+            for (id, config) in requests {
+                if let Some(window) =
+                    open_window(&mut app.world, &mut renderer, target, id, &config)
+                {
+                    os_windows.insert(window.id(), (id, window));
+                }
+            }
+
+            let mut windows = app.world.resource_mut::<Windows>();
+            let closed = os_windows
+                .values()
+                .filter(|(id, window)| {
+                    windows.get_mut(*id).map_or(false, |bevy_window| {
+                        bevy_window.drain_commands().fold(false, |closed, command| {
+                            apply_window_command(window, command) || closed
+                        })
+                    })
+                })
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+
+            for id in closed {
+                os_windows.retain(|_, (other, _)| *other != id);
+                renderer.remove_window(id);
+                app.world.resource_mut::<Windows>().remove(id);
+                app.world.send_event(WindowClosed { id });
+
+                if id == primary_id {
+                    *flow = ControlFlow::Exit;
+                }
+            }
         }
-        winit::event::Event::RedrawRequested(_) => {
+        // A single frame draws every window, so it can't wait on any one of them to redraw
+        winit::event::Event::RedrawEventsCleared => {
             renderer.do_frame(flow, &mut app.world);
         }
-        winit::event::Event::RedrawEventsCleared => {
-            window.request_redraw();
+        winit::event::Event::LoopDestroyed => {
+            renderer.save_pipeline_cache();
         }
         _ => *flow = ControlFlow::Poll,
     });
@@ -168,10 +793,19 @@ impl Plugin for RendererPlugin {
             .add_asset::<TextureImage>()
             .add_asset::<DisplayMaterial>()
             .add_event::<WindowSetting>()
+            .add_event::<OpenWindow>()
+            .add_event::<MeshUploadError>()
+            .add_event::<RendererError>()
+            .init_resource::<VertexAttributeLocations>()
+            .init_resource::<RendererSettings>()
+            .init_resource::<WindowConfig>()
             .set_runner(renderer_runner)
             .add_system_set_to_stage(
                 CoreStage::PreUpdate,
-                SystemSet::new().with_system(update_meshes),
+                // Its PendingMesh removals must not land after a newer upload started
+                SystemSet::new()
+                    .with_system(update_meshes)
+                    .with_system(finish_uploads.before(update_meshes)),
             )
             .add_system_to_stage(CoreStage::PostUpdate, update_window);
     }
@@ -4,12 +4,14 @@ pub trait Projection {
     fn compute_matrix(&self, dimensions: Vec2) -> Mat4;
 }
 
+#[derive(Clone)]
 pub struct PerspectiveProjection {
     pub fov: f32,
     pub near: f32,
     pub far: f32,
 }
 
+#[derive(Clone)]
 pub struct OrthographicProjection {
     pub near: f32,
     pub far: f32,
@@ -0,0 +1,93 @@
+use std::{env, fs, path::PathBuf, sync::Arc};
+
+use bevy::prelude::{debug, info, warn};
+use vulkano::{device::Device, pipeline::cache::PipelineCache, OomError};
+
+const MAGIC: &[u8; 8] = b"ALNYPLC1";
+const HEADER_SIZE: usize = MAGIC.len() + 3 * 4 + 16;
+
+// Drivers are supposed to reject foreign data themselves, but not all of them do
+fn header(device: &Device) -> Vec<u8> {
+    let properties = device.physical_device().properties();
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&properties.vendor_id.to_le_bytes());
+    header.extend_from_slice(&properties.device_id.to_le_bytes());
+    header.extend_from_slice(&properties.driver_version.to_le_bytes());
+    header.extend_from_slice(&properties.pipeline_cache_uuid);
+
+    header
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Caches"))
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
+
+    base.map(|base| base.join(env!("CARGO_PKG_NAME")))
+}
+
+fn cache_path() -> Option<PathBuf> {
+    cache_dir().map(|dir| dir.join("pipelines.bin"))
+}
+
+pub fn load_pipeline_cache(device: Arc<Device>) -> Result<Arc<PipelineCache>, OomError> {
+    let data = cache_path().and_then(|path| fs::read(path).ok());
+    let expected = header(&device);
+
+    match data {
+        Some(data) if data.len() > HEADER_SIZE && data[..HEADER_SIZE] == expected[..] => {
+            debug!("Loading {} bytes of pipeline cache", data.len() - HEADER_SIZE);
+            // Safe as long as the header matches, the data came from this very driver
+            unsafe { PipelineCache::with_data(device.clone(), &data[HEADER_SIZE..]) }.or_else(
+                |err| {
+                    warn!("Could not load pipeline cache, starting anew: {}", err);
+                    PipelineCache::empty(device)
+                },
+            )
+        }
+        Some(_) => {
+            info!("Pipeline cache belongs to a different device or driver, starting anew");
+            PipelineCache::empty(device)
+        }
+        None => PipelineCache::empty(device),
+    }
+}
+
+pub fn save_pipeline_cache(device: &Device, cache: &PipelineCache) {
+    let path = match cache_path() {
+        Some(path) => path,
+        None => {
+            warn!("No cache directory to save pipelines to");
+            return;
+        }
+    };
+
+    let mut data = header(device);
+    match cache.get_data() {
+        Ok(cache_data) => data.extend(cache_data),
+        Err(err) => {
+            warn!("Could not retrieve pipeline cache data: {}", err);
+            return;
+        }
+    }
+
+    // Written next to the cache and renamed over it, so a crash never leaves a torn file behind
+    let temp_path = path.with_extension("bin.tmp");
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&temp_path, data))
+        .and_then(|_| fs::rename(&temp_path, &path));
+    if let Err(err) = result {
+        warn!("Could not save pipeline cache to {:?}: {}", path, err);
+    }
+}
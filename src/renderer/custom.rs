@@ -0,0 +1,352 @@
+use std::{any::type_name, borrow::Cow, collections::HashMap, marker::PhantomData, sync::Arc};
+
+use bevy::{
+    prelude::{error, Assets, Component, GlobalTransform, Handle, With, World},
+    render::mesh::PrimitiveTopology,
+};
+use bytemuck::Pod;
+use vulkano::{
+    buffer::{BufferAccess, BufferUsage, CpuBufferPool},
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet},
+    device::Device,
+    pipeline::{GraphicsPipeline, Pipeline},
+    shader::{ShaderCreationError, ShaderModule},
+};
+
+use crate::data::VertexLayout;
+
+use super::{
+    error::RendererError,
+    material::TextureImage,
+    mesh::{DisplayMesh, PrimitiveStyle},
+    util::{self, PipelineKey},
+    VulkanContext,
+};
+
+pub enum ShaderSource {
+    SpirV(Cow<'static, [u32]>),
+    #[cfg(feature = "glsl")]
+    Glsl(Cow<'static, str>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+}
+
+// Shaders follow the layout of the built-in ones: view/projection in set 0, the uniform at
+// binding 0 of set 1 followed by one sampler per texture, the model in set 2. Unsafe because
+// SPIR-V shaders are handed to the driver as they are and must be valid modules
+pub unsafe trait CustomMaterial: Component {
+    type Uniform: Pod + Send + Sync;
+
+    fn vertex_shader() -> ShaderSource;
+    fn fragment_shader() -> ShaderSource;
+
+    fn uniform(&self) -> Self::Uniform;
+
+    fn textures(&self) -> Vec<Option<Handle<TextureImage>>> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum ShaderError {
+    #[cfg(feature = "glsl")]
+    Io(std::io::Error),
+    #[cfg(feature = "glsl")]
+    CompilerUnavailable,
+    #[cfg(feature = "glsl")]
+    Compile(shaderc::Error),
+    Create(ShaderCreationError),
+    MissingEntryPoint(ShaderStage),
+    MissingDescriptorSet(u32),
+}
+
+#[derive(Component)]
+pub struct UsesCustomMaterial;
+
+#[derive(Default)]
+pub struct CustomMaterialRegistry {
+    drawers: Vec<Box<dyn CustomMaterialDrawer>>,
+}
+
+pub(super) struct PassState<'a> {
+    pub vp_buffer: Arc<dyn BufferAccess>,
+    pub target: Option<&'a Handle<TextureImage>>,
+}
+
+trait CustomMaterialDrawer: Send + Sync {
+    fn draw(
+        &mut self,
+        renderer: &mut VulkanContext,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pass: &PassState,
+        world: &mut World,
+    );
+}
+
+type ShaderPair = (Arc<ShaderModule>, Arc<ShaderModule>);
+type CustomItem<'a, M> = (
+    &'a GlobalTransform,
+    &'a DisplayMesh,
+    &'a M,
+    Option<&'a PrimitiveStyle>,
+);
+
+enum Shaders {
+    Pending,
+    Loaded(Arc<ShaderModule>, Arc<ShaderModule>),
+    Failed,
+}
+
+struct CustomDrawer<M: CustomMaterial> {
+    shaders: Shaders,
+    pipelines: HashMap<PipelineKey, Arc<GraphicsPipeline>>,
+    uniform_pool: Option<CpuBufferPool<M::Uniform>>,
+    _marker: PhantomData<M>,
+}
+
+impl std::fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "glsl")]
+            Self::Io(err) => write!(f, "Could not read shader source: {}", err),
+            #[cfg(feature = "glsl")]
+            Self::CompilerUnavailable => write!(f, "Could not initialize the GLSL compiler"),
+            #[cfg(feature = "glsl")]
+            Self::Compile(err) => write!(f, "Shader compilation failed: {}", err),
+            Self::Create(err) => write!(f, "Could not create shader module: {}", err),
+            Self::MissingEntryPoint(stage) => {
+                write!(f, "{:?} shader has no \"main\" entry point", stage)
+            }
+            Self::MissingDescriptorSet(set) => {
+                write!(f, "Shaders do not declare descriptor set {}", set)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
+impl CustomMaterialRegistry {
+    pub fn register<M: CustomMaterial>(&mut self) {
+        self.drawers.push(Box::new(CustomDrawer::<M> {
+            shaders: Shaders::Pending,
+            pipelines: HashMap::new(),
+            uniform_pool: None,
+            _marker: PhantomData,
+        }));
+    }
+
+    pub(super) fn draw(
+        &mut self,
+        renderer: &mut VulkanContext,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pass: &PassState,
+        world: &mut World,
+    ) {
+        for drawer in &mut self.drawers {
+            drawer.draw(renderer, builder, pass, world);
+        }
+    }
+}
+
+// Safety: SPIR-V sources are not validated and must be valid modules, GLSL ones are compiled
+#[cfg_attr(not(feature = "glsl"), allow(unused_variables))]
+pub unsafe fn load_shader(
+    device: Arc<Device>,
+    source: &ShaderSource,
+    stage: ShaderStage,
+    name: &str,
+) -> Result<Arc<ShaderModule>, ShaderError> {
+    let words = match source {
+        ShaderSource::SpirV(words) => words.to_vec(),
+        #[cfg(feature = "glsl")]
+        ShaderSource::Glsl(source) => {
+            let kind = match stage {
+                ShaderStage::Vertex => shaderc::ShaderKind::Vertex,
+                ShaderStage::Fragment => shaderc::ShaderKind::Fragment,
+            };
+            let compiler = shaderc::Compiler::new().ok_or(ShaderError::CompilerUnavailable)?;
+            compiler
+                .compile_into_spirv(source, kind, name, "main", None)
+                .map_err(ShaderError::Compile)?
+                .as_binary()
+                .to_vec()
+        }
+    };
+
+    ShaderModule::from_words(device, &words).map_err(ShaderError::Create)
+}
+
+// The renderer binds view/projection, material and model at binding 0 of sets 0 to 2, a pair
+// that leaves any of them out would only fail once pipelines are built and drawn with
+pub fn check_interface(vs: &ShaderModule, fs: &ShaderModule) -> Result<(), ShaderError> {
+    let vs = vs
+        .entry_point("main")
+        .ok_or(ShaderError::MissingEntryPoint(ShaderStage::Vertex))?;
+    let fs = fs
+        .entry_point("main")
+        .ok_or(ShaderError::MissingEntryPoint(ShaderStage::Fragment))?;
+
+    for set in 0..3 {
+        let declared = [&vs, &fs].iter().any(|entry_point| {
+            entry_point
+                .descriptor_requirements()
+                .any(|(location, _)| location == (set, 0))
+        });
+
+        if !declared {
+            return Err(ShaderError::MissingDescriptorSet(set));
+        }
+    }
+
+    Ok(())
+}
+
+impl<M: CustomMaterial> CustomDrawer<M> {
+    fn shaders(&mut self, device: &Arc<Device>) -> Option<ShaderPair> {
+        if let Shaders::Pending = self.shaders {
+            let name = type_name::<M>();
+            // Safety: CustomMaterial implementors guarantee their SPIR-V is valid
+            let shaders = unsafe {
+                load_shader(
+                    device.clone(),
+                    &M::vertex_shader(),
+                    ShaderStage::Vertex,
+                    name,
+                )
+                .and_then(|vs| {
+                    let fs = load_shader(
+                        device.clone(),
+                        &M::fragment_shader(),
+                        ShaderStage::Fragment,
+                        name,
+                    )?;
+                    check_interface(&vs, &fs)?;
+                    Ok((vs, fs))
+                })
+            };
+
+            self.shaders = match shaders {
+                Ok((vs, fs)) => Shaders::Loaded(vs, fs),
+                Err(err) => {
+                    error!("Could not load shaders of {}: {}", name, err);
+                    Shaders::Failed
+                }
+            };
+        }
+
+        match &self.shaders {
+            Shaders::Loaded(vs, fs) => Some((vs.clone(), fs.clone())),
+            Shaders::Pending | Shaders::Failed => None,
+        }
+    }
+
+    fn pipeline(
+        &mut self,
+        renderer: &VulkanContext,
+        shaders: &ShaderPair,
+        layout: &VertexLayout,
+        topology: PrimitiveTopology,
+    ) -> Result<Arc<GraphicsPipeline>, RendererError> {
+        let key = PipelineKey {
+            layout: layout.clone(),
+            topology,
+            blend: false,
+        };
+        if let Some(pipeline) = self.pipelines.get(&key) {
+            return Ok(pipeline.clone());
+        }
+
+        let pipeline = util::create_pipeline(
+            renderer.render_pass.clone(),
+            shaders.0.clone(),
+            shaders.1.clone(),
+            &key,
+            renderer.pipeline_cache.clone(),
+            renderer.device.clone(),
+        )?;
+        self.pipelines.insert(key, pipeline.clone());
+        Ok(pipeline)
+    }
+
+    fn draw_item(
+        &mut self,
+        renderer: &mut VulkanContext,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline: &Arc<GraphicsPipeline>,
+        pass: &PassState,
+        textures: &Assets<TextureImage>,
+        (transform, mesh, material, style): CustomItem<M>,
+    ) -> Result<(), RendererError> {
+        let uniform_pool = self.uniform_pool.get_or_insert_with(|| {
+            CpuBufferPool::new(renderer.device.clone(), BufferUsage::uniform_buffer())
+        });
+
+        let mut writes = vec![WriteDescriptorSet::buffer(
+            0,
+            uniform_pool.next(material.uniform())?,
+        )];
+        for (binding, handle) in (1..).zip(material.textures()) {
+            let (texture, sampler_settings) =
+                renderer.texture_binding(handle.as_ref(), None, textures, pass.target);
+            let sampler = renderer.sampler(&sampler_settings)?;
+
+            writes.push(WriteDescriptorSet::image_view_sampler(
+                binding, texture, sampler,
+            ));
+        }
+
+        let material_set =
+            PersistentDescriptorSet::new(pipeline.layout().set_layouts()[1].clone(), writes)?;
+        renderer.draw_mesh(builder, pipeline, material_set, transform, mesh, style)
+    }
+}
+
+impl<M: CustomMaterial> CustomMaterialDrawer for CustomDrawer<M> {
+    fn draw(
+        &mut self,
+        renderer: &mut VulkanContext,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pass: &PassState,
+        world: &mut World,
+    ) {
+        let shaders = match self.shaders(&renderer.device) {
+            Some(shaders) => shaders,
+            None => return,
+        };
+
+        let mut query = world.query_filtered::<(
+            &GlobalTransform,
+            &DisplayMesh,
+            &M,
+            Option<&PrimitiveStyle>,
+        ), With<UsesCustomMaterial>>();
+        let textures = world.resource::<Assets<TextureImage>>();
+
+        let mut batches = HashMap::<_, Vec<_>>::new();
+        for item in query.iter(world) {
+            batches
+                .entry((item.1.layout(), item.1.topology()))
+                .or_default()
+                .push(item);
+        }
+
+        for ((layout, topology), items) in batches {
+            let pipeline = self.pipeline(renderer, &shaders, layout, topology);
+            renderer.record_batch(
+                builder,
+                pipeline,
+                pass.vp_buffer.clone(),
+                items,
+                |renderer, builder, pipeline, item| {
+                    self.draw_item(renderer, builder, pipeline, pass, textures, item)
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::{env, sync::Arc};
+
+use bevy::prelude::{debug, error, info, trace, warn};
+use vulkano::instance::{
+    debug::{DebugUtilsMessenger, DebugUtilsMessengerCreateInfo, Message},
+    layers_list, Instance,
+};
+
+use super::RendererSettings;
+
+const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
+const VALIDATION_ENV: &str = "ALNYAN_VALIDATION";
+
+// The environment can turn validation on for a build that doesn't ask for it, or off again
+pub fn validation_enabled(settings: &RendererSettings) -> bool {
+    match env::var(VALIDATION_ENV) {
+        Ok(value) => !matches!(value.as_str(), "" | "0" | "false"),
+        Err(_) => settings.validation,
+    }
+}
+
+pub fn validation_layers(enabled: bool) -> Vec<String> {
+    if !enabled {
+        return Vec::new();
+    }
+
+    let available = layers_list().map_or(false, |mut layers| {
+        layers.any(|layer| layer.name() == VALIDATION_LAYER)
+    });
+    if !available {
+        warn!("Validation requested, but {} is not installed", VALIDATION_LAYER);
+        return Vec::new();
+    }
+
+    info!("Enabling {}", VALIDATION_LAYER);
+    vec![VALIDATION_LAYER.to_owned()]
+}
+
+pub fn create_messenger(
+    instance: Arc<Instance>,
+    settings: &RendererSettings,
+) -> Option<DebugUtilsMessenger> {
+    if !instance.enabled_extensions().ext_debug_utils {
+        return None;
+    }
+
+    let result = unsafe {
+        DebugUtilsMessenger::new(
+            instance,
+            DebugUtilsMessengerCreateInfo {
+                message_severity: settings.debug_severity,
+                message_type: settings.debug_types,
+                ..DebugUtilsMessengerCreateInfo::user_callback(Arc::new(log_message))
+            },
+        )
+    };
+
+    result
+        .map_err(|err| warn!("Could not create debug messenger: {}", err))
+        .ok()
+}
+
+// Drivers and the loader are chatty at the information level, so it only shows up in debug logs
+fn log_message(message: &Message) {
+    let layer = message.layer_prefix.unwrap_or("vulkan");
+    let ty = if message.ty.validation {
+        "validation"
+    } else if message.ty.performance {
+        "performance"
+    } else {
+        "general"
+    };
+
+    if message.severity.error {
+        error!("[{} {}] {}", layer, ty, message.description);
+    } else if message.severity.warning {
+        warn!("[{} {}] {}", layer, ty, message.description);
+    } else if message.severity.information {
+        debug!("[{} {}] {}", layer, ty, message.description);
+    } else {
+        trace!("[{} {}] {}", layer, ty, message.description);
+    }
+}
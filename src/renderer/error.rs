@@ -0,0 +1,144 @@
+use vulkano::{
+    buffer::immutable::ImmutableBufferCreationError,
+    command_buffer::{
+        BuildError, CommandBufferBeginError, CommandBufferExecError, CopyError, DrawIndexedError,
+        RenderPassError,
+    },
+    descriptor_set::DescriptorSetCreationError,
+    device::{physical::SurfacePropertiesError, DeviceCreationError},
+    image::{
+        immutable::ImmutableImageCreationError, view::ImageViewCreationError, ImageCreationError,
+    },
+    instance::InstanceCreationError,
+    memory::DeviceMemoryAllocationError,
+    pipeline::graphics::GraphicsPipelineCreationError,
+    render_pass::{FramebufferCreationError, RenderPassCreationError},
+    sampler::SamplerCreationError,
+    shader::ShaderCreationError,
+    swapchain::{AcquireError, SurfaceCreationError, SwapchainCreationError},
+    sync::FlushError,
+    OomError,
+};
+
+#[derive(Debug)]
+pub enum RendererError {
+    Instance(InstanceCreationError),
+    Surface(SurfaceCreationError),
+    SurfaceProperties(SurfacePropertiesError),
+    NoSuitableDevice,
+    UnsupportedSurface,
+    Device(DeviceCreationError),
+    Swapchain(SwapchainCreationError),
+    Acquire(AcquireError),
+    Oom(OomError),
+    Memory(DeviceMemoryAllocationError),
+    Buffer(ImmutableBufferCreationError),
+    Image(ImageCreationError),
+    ImmutableImage(ImmutableImageCreationError),
+    ImageView(ImageViewCreationError),
+    Sampler(SamplerCreationError),
+    RenderPass(RenderPassCreationError),
+    Framebuffer(FramebufferCreationError),
+    Shader(ShaderCreationError),
+    Pipeline(GraphicsPipelineCreationError),
+    DescriptorSet(DescriptorSetCreationError),
+    CommandBuffer(CommandBufferBeginError),
+    RenderPassCommand(RenderPassError),
+    Draw(DrawIndexedError),
+    Copy(CopyError),
+    Build(BuildError),
+    Execute(CommandBufferExecError),
+    Flush(FlushError),
+}
+
+impl RendererError {
+    // The swapchain no longer matches the surface, recreating it is enough to recover
+    pub const fn is_out_of_date(&self) -> bool {
+        matches!(
+            self,
+            Self::Acquire(AcquireError::OutOfDate)
+                | Self::Flush(FlushError::OutOfDate)
+                | Self::Swapchain(SwapchainCreationError::ImageExtentNotSupported { .. })
+        )
+    }
+}
+
+impl std::fmt::Display for RendererError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Instance(err) => write!(f, "Could not create Vulkan instance: {}", err),
+            Self::Surface(err) => write!(f, "Could not create window surface: {}", err),
+            Self::SurfaceProperties(err) => write!(f, "Could not query surface: {}", err),
+            Self::NoSuitableDevice => {
+                write!(f, "No device supports graphics and presentation to the window")
+            }
+            Self::UnsupportedSurface => {
+                write!(f, "The selected device cannot present to this window")
+            }
+            Self::Device(err) => write!(f, "Could not create logical device: {}", err),
+            Self::Swapchain(err) => write!(f, "Could not create swapchain: {}", err),
+            Self::Acquire(err) => write!(f, "Could not acquire swapchain image: {}", err),
+            Self::Oom(err) => write!(f, "{}", err),
+            Self::Memory(err) => write!(f, "Could not allocate buffer: {}", err),
+            Self::Buffer(err) => write!(f, "Could not create buffer: {}", err),
+            Self::Image(err) => write!(f, "Could not create image: {}", err),
+            Self::ImmutableImage(err) => write!(f, "Could not create image: {}", err),
+            Self::ImageView(err) => write!(f, "Could not create image view: {}", err),
+            Self::Sampler(err) => write!(f, "Could not create sampler: {}", err),
+            Self::RenderPass(err) => write!(f, "Could not create render pass: {}", err),
+            Self::Framebuffer(err) => write!(f, "Could not create framebuffer: {}", err),
+            Self::Shader(err) => write!(f, "Could not create shader module: {}", err),
+            Self::Pipeline(err) => write!(f, "Could not create pipeline: {}", err),
+            Self::DescriptorSet(err) => write!(f, "Could not create descriptor set: {}", err),
+            Self::CommandBuffer(err) => write!(f, "Could not begin command buffer: {}", err),
+            Self::RenderPassCommand(err) => write!(f, "Render pass command failed: {}", err),
+            Self::Draw(err) => write!(f, "Draw command failed: {}", err),
+            Self::Copy(err) => write!(f, "Copy command failed: {}", err),
+            Self::Build(err) => write!(f, "Could not build command buffer: {}", err),
+            Self::Execute(err) => write!(f, "Could not execute command buffer: {}", err),
+            Self::Flush(err) => write!(f, "Could not submit work: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {}
+
+macro_rules! impl_from {
+    ($($variant:ident($error:ty)),* $(,)?) => {
+        $(
+            impl From<$error> for RendererError {
+                fn from(err: $error) -> Self {
+                    Self::$variant(err)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    Instance(InstanceCreationError),
+    Surface(SurfaceCreationError),
+    SurfaceProperties(SurfacePropertiesError),
+    Device(DeviceCreationError),
+    Swapchain(SwapchainCreationError),
+    Acquire(AcquireError),
+    Oom(OomError),
+    Memory(DeviceMemoryAllocationError),
+    Buffer(ImmutableBufferCreationError),
+    Image(ImageCreationError),
+    ImmutableImage(ImmutableImageCreationError),
+    ImageView(ImageViewCreationError),
+    Sampler(SamplerCreationError),
+    RenderPass(RenderPassCreationError),
+    Framebuffer(FramebufferCreationError),
+    Shader(ShaderCreationError),
+    Pipeline(GraphicsPipelineCreationError),
+    DescriptorSet(DescriptorSetCreationError),
+    CommandBuffer(CommandBufferBeginError),
+    RenderPassCommand(RenderPassError),
+    Draw(DrawIndexedError),
+    Copy(CopyError),
+    Build(BuildError),
+    Execute(CommandBufferExecError),
+    Flush(FlushError),
+);
@@ -5,18 +5,59 @@ use bevy::{
     prelude::{Color, Component, Handle},
     reflect::TypeUuid,
 };
+use serde::Deserialize;
 use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::{
+        AutoCommandBufferBuilder, BufferImageCopy, CommandBufferExecFuture, CommandBufferUsage,
+        CopyBufferToImageInfo, PrimaryAutoCommandBuffer, PrimaryCommandBuffer,
+    },
     device::Queue,
     format::Format,
-    image::{view::ImageView, ImageDimensions, ImmutableImage, MipmapsCount},
-    sync::GpuFuture,
+    image::{
+        view::{ImageView, ImageViewCreateInfo},
+        ImageAspects, ImageCreateFlags, ImageDimensions, ImageLayout, ImageSubresourceLayers,
+        ImageUsage, ImageViewAbstract, ImmutableImage, MipmapsCount,
+    },
+    sampler::{ComponentMapping, ComponentSwizzle, Filter, SamplerAddressMode, SamplerMipmapMode},
+    sync::NowFuture,
+    DeviceSize,
 };
 
-#[derive(Component, TypeUuid)]
+use super::error::RendererError;
+
+#[derive(Component, TypeUuid, Clone)]
 #[uuid = "de491a16-cf4c-4ef9-8f02-0f7837b4dea8"]
 pub struct DisplayMaterial {
     pub k_diffuse: Color,
     pub k_diffuse_map: Option<Handle<TextureImage>>,
+    pub k_diffuse_sampler: Option<SamplerSettings>,
+    pub k_specular: Color,
+    pub shininess: f32,
+    pub alpha_mode: AlphaMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum AlphaMode {
+    Opaque,
+    Mask(f32),
+    Blend,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerSettings {
+    pub mag_filter: Filter,
+    pub min_filter: Filter,
+    pub mipmap_mode: SamplerMipmapMode,
+    pub address_mode: [SamplerAddressMode; 3],
+    pub anisotropy: Option<f32>,
+    pub lod_bias: f32,
 }
 
 #[derive(Component, TypeUuid)]
@@ -25,7 +66,111 @@ pub struct TextureImage {
     pub data: Vec<u8>,
     pub format: Format,
     pub dimensions: UVec2,
-    pub image: Option<Arc<ImageView<ImmutableImage>>>,
+    pub mip_levels: u32,
+    pub render_target: bool,
+    pub sampler: SamplerSettings,
+    pub image: Option<Arc<dyn ImageViewAbstract>>,
+    pub upload_error: Option<TextureError>,
+    pub(crate) pending_upload: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum TextureError {
+    UnsupportedFormat(Format),
+    TruncatedData,
+    Upload(RendererError),
+}
+
+pub type TextureUploadFuture = CommandBufferExecFuture<NowFuture, PrimaryAutoCommandBuffer>;
+
+impl Default for DisplayMaterial {
+    fn default() -> Self {
+        Self {
+            k_diffuse: Color::WHITE,
+            k_diffuse_map: None,
+            k_diffuse_sampler: None,
+            k_specular: Color::BLACK,
+            shininess: 32.0,
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+}
+
+impl Default for AlphaMode {
+    fn default() -> Self {
+        Self::Opaque
+    }
+}
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        Self {
+            mag_filter: Filter::Linear,
+            min_filter: Filter::Linear,
+            mipmap_mode: SamplerMipmapMode::Linear,
+            address_mode: [SamplerAddressMode::Repeat; 3],
+            anisotropy: Some(16.0),
+            lod_bias: 0.0,
+        }
+    }
+}
+
+impl DisplayMaterial {
+    pub fn texture_slots(&self) -> impl Iterator<Item = (&Handle<TextureImage>, ColorSpace)> {
+        self.k_diffuse_map
+            .iter()
+            .map(|handle| (handle, ColorSpace::Srgb))
+    }
+}
+
+impl ColorSpace {
+    const FORMAT_PAIRS: [(Format, Format); 13] = [
+        (Format::R8_SRGB, Format::R8_UNORM),
+        (Format::R8G8_SRGB, Format::R8G8_UNORM),
+        (Format::R8G8B8_SRGB, Format::R8G8B8_UNORM),
+        (Format::B8G8R8_SRGB, Format::B8G8R8_UNORM),
+        (Format::R8G8B8A8_SRGB, Format::R8G8B8A8_UNORM),
+        (Format::B8G8R8A8_SRGB, Format::B8G8R8A8_UNORM),
+        (Format::A8B8G8R8_SRGB_PACK32, Format::A8B8G8R8_UNORM_PACK32),
+        (Format::BC1_RGB_SRGB_BLOCK, Format::BC1_RGB_UNORM_BLOCK),
+        (Format::BC1_RGBA_SRGB_BLOCK, Format::BC1_RGBA_UNORM_BLOCK),
+        (Format::BC2_SRGB_BLOCK, Format::BC2_UNORM_BLOCK),
+        (Format::BC3_SRGB_BLOCK, Format::BC3_UNORM_BLOCK),
+        (Format::BC7_SRGB_BLOCK, Format::BC7_UNORM_BLOCK),
+        (Format::ETC2_R8G8B8A8_SRGB_BLOCK, Format::ETC2_R8G8B8A8_UNORM_BLOCK),
+    ];
+
+    pub fn of(format: Format) -> Option<Self> {
+        Self::FORMAT_PAIRS.iter().find_map(|&(srgb, linear)| {
+            if format == srgb {
+                Some(Self::Srgb)
+            } else if format == linear {
+                Some(Self::Linear)
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn apply(self, format: Format) -> Format {
+        Self::FORMAT_PAIRS
+            .iter()
+            .find(|&&(srgb, linear)| format == srgb || format == linear)
+            .map_or(format, |&(srgb, linear)| match self {
+                Self::Srgb => srgb,
+                Self::Linear => linear,
+            })
+    }
+}
+
+impl SamplerSettings {
+    pub fn clamp_to_edge() -> Self {
+        Self {
+            address_mode: [SamplerAddressMode::ClampToEdge; 3],
+            anisotropy: None,
+            ..Default::default()
+        }
+    }
 }
 
 impl TextureImage {
@@ -34,29 +179,275 @@ impl TextureImage {
             data: Vec::from(data),
             dimensions,
             format,
+            mip_levels: 1,
+            render_target: false,
+            sampler: SamplerSettings::default(),
             image: None,
+            upload_error: None,
+            pending_upload: None,
+        }
+    }
+
+    // Materials sample 2D textures only, so there is a single layer
+    pub fn from_levels(data: &[u8], format: Format, dimensions: UVec2, mip_levels: u32) -> Self {
+        Self {
+            mip_levels,
+            ..Self::from_bytes(data, format, dimensions)
+        }
+    }
+
+    pub fn render_target(dimensions: UVec2) -> Self {
+        Self {
+            data: Vec::new(),
+            dimensions,
+            format: Format::B8G8R8A8_SRGB,
+            mip_levels: 1,
+            render_target: true,
+            sampler: SamplerSettings::clamp_to_edge(),
+            image: None,
+            upload_error: None,
+            pending_upload: None,
+        }
+    }
+
+    pub fn with_sampler(mut self, sampler: SamplerSettings) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    pub fn color_space(&self) -> Option<ColorSpace> {
+        ColorSpace::of(self.format)
+    }
+
+    pub fn set_color_space(&mut self, color_space: ColorSpace) {
+        self.format = color_space.apply(self.format);
+    }
+
+    pub fn needs_upload(&self) -> bool {
+        self.image.is_none()
+            && self.upload_error.is_none()
+            && self.pending_upload.is_none()
+            && !self.render_target
+    }
+
+    // Sampling from one and two channel formats is optional, the sRGB ones especially.
+    // Levels are plain runs of texels, so they can be expanded all at once
+    fn expand_to_rgba(&self) -> Option<Self> {
+        let channels = match self.format {
+            Format::R8_SRGB | Format::R8_UNORM => 1,
+            Format::R8G8_SRGB | Format::R8G8_UNORM => 2,
+            _ => return None,
+        };
+
+        let data = self
+            .data
+            .chunks_exact(channels)
+            .flat_map(|texel| {
+                let alpha = if channels == 2 { texel[1] } else { u8::MAX };
+                [texel[0], texel[0], texel[0], alpha]
+            })
+            .collect::<Vec<_>>();
+        let format = match self.color_space() {
+            Some(ColorSpace::Linear) => Format::R8G8B8A8_UNORM,
+            _ => Format::R8G8B8A8_SRGB,
+        };
+
+        Some(
+            Self::from_levels(&data, format, self.dimensions, self.mip_levels)
+                .with_sampler(self.sampler),
+        )
+    }
+
+    // Compressed data and files with their own mip chains are copied level by level
+    fn has_stored_levels(&self) -> bool {
+        self.mip_levels > 1 || self.format.compression().is_some()
+    }
+
+    pub fn upload(
+        &self,
+        queue: Arc<Queue>,
+    ) -> Result<(Arc<dyn ImageViewAbstract>, TextureUploadFuture), TextureError> {
+        let features = queue
+            .device()
+            .physical_device()
+            .format_properties(self.format)
+            .optimal_tiling_features;
+
+        if !features.sampled_image {
+            return match self.expand_to_rgba() {
+                Some(expanded) => expanded.upload(queue),
+                None => Err(TextureError::UnsupportedFormat(self.format)),
+            };
         }
+
+        let (image, init) = if self.has_stored_levels() {
+            self.upload_stored_levels(queue)?
+        } else {
+            // Mip chains are generated with linear blits, which not every format supports
+            let mipmaps =
+                if features.blit_src && features.blit_dst && features.sampled_image_filter_linear {
+                    MipmapsCount::Log2
+                } else {
+                    MipmapsCount::One
+                };
+
+            ImmutableImage::from_iter(
+                self.data.clone(),
+                ImageDimensions::Dim2d {
+                    width: self.dimensions.x,
+                    height: self.dimensions.y,
+                    array_layers: 1,
+                },
+                mipmaps,
+                self.format,
+                queue,
+            )
+            .map_err(RendererError::from)?
+        };
+
+        let view = ImageView::new(
+            image.clone(),
+            ImageViewCreateInfo {
+                component_mapping: component_mapping(self.format),
+                ..ImageViewCreateInfo::from_image(&image)
+            },
+        )
+        .map_err(RendererError::from)?;
+
+        Ok((view, init))
     }
 
-    pub fn upload_to_gpu(&mut self, queue: Arc<Queue>) {
-        let (image, init) = ImmutableImage::from_iter(
-            self.data.clone(),
+    fn upload_stored_levels(
+        &self,
+        queue: Arc<Queue>,
+    ) -> Result<(Arc<ImmutableImage>, TextureUploadFuture), TextureError> {
+        let device = queue.device().clone();
+
+        let mut regions = Vec::new();
+        let mut offset = 0;
+        for mip_level in 0..self.mip_levels {
+            let width = (self.dimensions.x >> mip_level).max(1);
+            let height = (self.dimensions.y >> mip_level).max(1);
+
+            regions.push(BufferImageCopy {
+                buffer_offset: offset as DeviceSize,
+                image_subresource: ImageSubresourceLayers {
+                    aspects: ImageAspects {
+                        color: true,
+                        ..ImageAspects::none()
+                    },
+                    mip_level,
+                    array_layers: 0..1,
+                },
+                image_extent: [width, height, 1],
+                ..Default::default()
+            });
+
+            offset += level_size(self.format, width, height);
+        }
+
+        if offset > self.data.len() {
+            return Err(TextureError::TruncatedData);
+        }
+
+        let (image, init) = ImmutableImage::uninitialized(
+            device.clone(),
             ImageDimensions::Dim2d {
                 width: self.dimensions.x,
                 height: self.dimensions.y,
                 array_layers: 1,
             },
-            MipmapsCount::One,
-            Format::R8G8B8A8_UNORM,
-            queue,
+            self.format,
+            MipmapsCount::Specific(self.mip_levels),
+            ImageUsage {
+                transfer_dst: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+            ImageCreateFlags::none(),
+            ImageLayout::ShaderReadOnlyOptimal,
+            device.active_queue_families(),
         )
-        .unwrap();
+        .map_err(RendererError::from)?;
+
+        let source = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage::transfer_src(),
+            false,
+            self.data[..offset].iter().copied(),
+        )
+        .map_err(RendererError::from)?;
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            device,
+            queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .map_err(RendererError::from)?;
+        builder
+            .copy_buffer_to_image(CopyBufferToImageInfo {
+                regions: regions.into(),
+                ..CopyBufferToImageInfo::buffer_image(source, init)
+            })
+            .map_err(RendererError::from)?;
+
+        let future = builder
+            .build()
+            .map_err(RendererError::from)?
+            .execute(queue)
+            .map_err(RendererError::from)?;
+
+        Ok((image, future))
+    }
+}
+
+pub fn level_size(format: Format, width: u32, height: u32) -> usize {
+    let [block_width, block_height, _] = format.block_extent();
+    let blocks_x = (width + block_width - 1) / block_width;
+    let blocks_y = (height + block_height - 1) / block_height;
+
+    (blocks_x * blocks_y) as usize * format.block_size().unwrap_or(0) as usize
+}
+
+impl std::fmt::Display for TextureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedFormat(format) => {
+                write!(f, "{:?} is not supported for sampling on this device", format)
+            }
+            Self::TruncatedData => write!(f, "Texture data is shorter than its levels require"),
+            Self::Upload(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for TextureError {}
+
+impl From<RendererError> for TextureError {
+    fn from(err: RendererError) -> Self {
+        Self::Upload(err)
+    }
+}
 
-        init.then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
-            .unwrap();
+// Grayscale images are stored in one or two channels, expand them back to RGB(A)
+fn component_mapping(format: Format) -> ComponentMapping {
+    let gray = ComponentMapping {
+        r: ComponentSwizzle::Red,
+        g: ComponentSwizzle::Red,
+        b: ComponentSwizzle::Red,
+        a: ComponentSwizzle::One,
+    };
 
-        self.image = Some(ImageView::new_default(image).unwrap());
+    match format {
+        Format::R8_SRGB
+        | Format::R8_UNORM
+        | Format::R16_UNORM
+        | Format::R32_SFLOAT
+        | Format::BC4_UNORM_BLOCK => gray,
+        Format::R8G8_SRGB | Format::R8G8_UNORM | Format::R16G16_UNORM => ComponentMapping {
+            a: ComponentSwizzle::Green,
+            ..gray
+        },
+        _ => ComponentMapping::identity(),
     }
 }
@@ -1,54 +1,284 @@
 use std::sync::Arc;
 
-use bevy::prelude::Component;
+use bevy::{
+    math::Vec3,
+    prelude::{debug, warn, Component, Entity, Handle, Mesh},
+    render::{mesh::PrimitiveTopology, render_resource::VertexFormat},
+};
 use vulkano::{
-    buffer::{BufferUsage, ImmutableBuffer},
-    device::Queue, sync::GpuFuture,
+    buffer::{BufferAccess, BufferUsage, ImmutableBuffer, TypedBufferAccess},
+    device::Queue,
+    sync::GpuFuture,
+};
+
+use crate::data::{
+    convert_vertex_format, VertexAttribute, VertexAttributeLocations, VertexLayout,
+    LOCATION_NORMAL,
 };
 
-use crate::data::Vertex;
+use super::error::RendererError;
 
 #[derive(Component)]
 pub struct DisplayMesh {
-    vertices: Arc<ImmutableBuffer<[Vertex]>>,
-    indices: Arc<ImmutableBuffer<[u32]>>,
+    layout: VertexLayout,
+    topology: PrimitiveTopology,
+    vertices: Vec<Arc<ImmutableBuffer<[u8]>>>,
+    indices: IndexBuffer,
+}
+
+pub enum IndexBuffer {
+    U16(Arc<ImmutableBuffer<[u16]>>),
+    U32(Arc<ImmutableBuffer<[u32]>>),
+}
+
+#[derive(Component, Clone, Copy)]
+pub struct PrimitiveStyle {
+    pub point_size: f32,
+    pub line_width: f32,
+}
+
+pub struct PreparedMesh {
+    pub layout: VertexLayout,
+    pub topology: PrimitiveTopology,
+    pub vertex_count: usize,
+    pub attributes: Vec<Vec<u8>>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Component)]
+pub struct InvalidMesh;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeshError {
+    MissingPositions,
+    UnsupportedPositionFormat,
+    Empty,
+    IndexOutOfBounds(u32),
+}
+
+pub struct MeshUploadError {
+    pub entity: Entity,
+    pub mesh: Handle<Mesh>,
+    pub error: MeshError,
 }
 
 impl DisplayMesh {
-    pub fn new<V: IntoIterator<Item = Vertex>, I: IntoIterator<Item = u32>>(
-        vertices: V,
-        indices: I,
+    pub fn upload(
+        prepared: PreparedMesh,
         queue: Arc<Queue>,
-    ) -> Self
-    where
-        V::IntoIter: ExactSizeIterator,
-        I::IntoIter: ExactSizeIterator,
-    {
-        let (vertices, init) =
-            ImmutableBuffer::from_iter(vertices, BufferUsage::vertex_buffer(), queue.clone())
-                .unwrap();
+    ) -> Result<(Self, impl GpuFuture + Send), RendererError> {
+        // Indices are validated against the vertex count, so they all fit when this holds
+        let short_indices = prepared.vertex_count <= u16::MAX as usize + 1;
+
+        let mut future = vulkano::sync::now(queue.device().clone()).boxed_send();
+        let mut vertices = Vec::with_capacity(prepared.attributes.len());
+        for data in prepared.attributes {
+            let (buffer, init) =
+                ImmutableBuffer::from_iter(data, BufferUsage::vertex_buffer(), queue.clone())?;
+            vertices.push(buffer);
+            future = future.join(init).boxed_send();
+        }
 
-        init.then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
-            .unwrap();
+        let (indices, indices_init) = if short_indices {
+            let (buffer, init) = ImmutableBuffer::from_iter(
+                prepared.indices.into_iter().map(|i| i as u16),
+                BufferUsage::index_buffer(),
+                queue,
+            )?;
+            (IndexBuffer::U16(buffer), init)
+        } else {
+            let (buffer, init) =
+                ImmutableBuffer::from_iter(prepared.indices, BufferUsage::index_buffer(), queue)?;
+            (IndexBuffer::U32(buffer), init)
+        };
 
-        let (indices, init) =
-            ImmutableBuffer::from_iter(indices, BufferUsage::index_buffer(), queue).unwrap();
+        let mesh = Self {
+            layout: prepared.layout,
+            topology: prepared.topology,
+            vertices,
+            indices,
+        };
 
-        init.then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
-            .unwrap();
+        Ok((mesh, future.join(indices_init)))
+    }
 
-        Self { vertices, indices }
+    pub const fn layout(&self) -> &VertexLayout {
+        &self.layout
     }
 
-    pub const fn indices(&self) -> &Arc<ImmutableBuffer<[u32]>> {
+    pub const fn topology(&self) -> PrimitiveTopology {
+        self.topology
+    }
+
+    pub const fn indices(&self) -> &IndexBuffer {
         &self.indices
     }
 
-    pub const fn vertices(&self) -> &Arc<ImmutableBuffer<[Vertex]>> {
-        &self.vertices
+    pub fn vertex_buffers(&self) -> Vec<Arc<dyn BufferAccess>> {
+        self.vertices
+            .iter()
+            .map(|buffer| buffer.clone() as Arc<dyn BufferAccess>)
+            .collect()
+    }
+}
+
+impl Default for PrimitiveStyle {
+    fn default() -> Self {
+        Self {
+            point_size: 1.0,
+            line_width: 1.0,
+        }
+    }
+}
+
+impl IndexBuffer {
+    pub fn count(&self) -> u32 {
+        match self {
+            Self::U16(buffer) => buffer.len() as u32,
+            Self::U32(buffer) => buffer.len() as u32,
+        }
+    }
+}
+
+impl std::fmt::Display for MeshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingPositions => write!(f, "Mesh has no vertex positions"),
+            Self::UnsupportedPositionFormat => write!(f, "Vertex positions are not float x3"),
+            Self::Empty => write!(f, "Mesh has no vertices or no indices"),
+            Self::IndexOutOfBounds(index) => write!(f, "Index {} is out of bounds", index),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+pub fn prepare_mesh(
+    mesh: &Mesh,
+    locations: &VertexAttributeLocations,
+) -> Result<PreparedMesh, MeshError> {
+    let positions = mesh
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .ok_or(MeshError::MissingPositions)?
+        .as_float3()
+        .ok_or(MeshError::UnsupportedPositionFormat)?;
+    let vertex_count = positions.len();
+
+    if vertex_count == 0 {
+        return Err(MeshError::Empty);
+    }
+
+    let indices: Vec<u32> = match mesh.indices() {
+        Some(indices) => indices.iter().map(|i| i as u32).collect(),
+        None => (0..vertex_count as u32).collect(),
+    };
+
+    if indices.is_empty() {
+        return Err(MeshError::Empty);
+    }
+    if let Some(&index) = indices.iter().find(|&&i| i as usize >= vertex_count) {
+        return Err(MeshError::IndexOutOfBounds(index));
+    }
+
+    let mut layout = Vec::new();
+    let mut attributes = Vec::new();
+
+    for (id, values) in mesh.attributes() {
+        let location = match locations.get(id) {
+            Some(location) => location,
+            None => {
+                debug!("Skipping vertex attribute {:?} without a shader location", id);
+                continue;
+            }
+        };
+
+        if values.len() != vertex_count {
+            warn!(
+                "Skipping vertex attribute {:?}: {} values for {} vertices",
+                id,
+                values.len(),
+                vertex_count
+            );
+            continue;
+        }
+        if location == LOCATION_NORMAL && values.as_float3().is_none() {
+            continue;
+        }
+
+        layout.push(VertexAttribute {
+            location,
+            format: convert_vertex_format(VertexFormat::from(values)),
+        });
+        attributes.push(values.get_bytes().to_vec());
+    }
+
+    // Lighting needs real normals, anything else missing is defaulted at draw time
+    let has_normals = layout.iter().any(|a| a.location == LOCATION_NORMAL);
+    let triangles = triangles(mesh.primitive_topology(), &indices).filter(|_| !has_normals);
+    if let Some(triangles) = triangles {
+        let normals = generate_normals(positions, &triangles);
+
+        layout.push(VertexAttribute {
+            location: LOCATION_NORMAL,
+            format: convert_vertex_format(VertexFormat::Float32x3),
+        });
+        attributes.push(bytemuck::cast_slice(&normals).to_vec());
+    }
+
+    // Buffers are bound in layout order, so sort both together
+    let mut combined = layout.into_iter().zip(attributes).collect::<Vec<_>>();
+    combined.sort_by_key(|(attribute, _)| attribute.location);
+    let (layout, attributes): (Vec<_>, Vec<_>) = combined.into_iter().unzip();
+
+    Ok(PreparedMesh {
+        layout: VertexLayout::new(layout),
+        topology: mesh.primitive_topology(),
+        vertex_count,
+        attributes,
+        indices,
+    })
+}
+
+// None for points and lines, which have no faces to take normals from
+fn triangles(topology: PrimitiveTopology, indices: &[u32]) -> Option<Vec<[u32; 3]>> {
+    match topology {
+        PrimitiveTopology::TriangleList => Some(
+            indices
+                .chunks_exact(3)
+                .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+                .collect(),
+        ),
+        // Every other triangle of a strip is wound the other way around
+        PrimitiveTopology::TriangleStrip => Some(
+            indices
+                .windows(3)
+                .enumerate()
+                .map(|(i, triangle)| match i % 2 {
+                    0 => [triangle[0], triangle[1], triangle[2]],
+                    _ => [triangle[1], triangle[0], triangle[2]],
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+// Area-weighted vertex normals. Non-indexed lists get sequential indices, so every vertex
+// belongs to a single triangle and this degenerates into flat shading for them.
+fn generate_normals(positions: &[[f32; 3]], triangles: &[[u32; 3]]) -> Vec<[f32; 3]> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+
+    for triangle in triangles {
+        let [a, b, c] = triangle.map(|i| Vec3::from(positions[i as usize]));
+        let normal = (b - a).cross(c - a);
+
+        for &i in triangle {
+            normals[i as usize] += normal;
+        }
     }
+
+    normals
+        .into_iter()
+        .map(|normal| normal.normalize_or_zero().into())
+        .collect()
 }
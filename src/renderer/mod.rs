@@ -1,86 +1,240 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use bevy::{
-    math::Mat4,
-    prelude::{Assets, Transform, World},
+    asset::{AssetEvent, HandleId},
+    ecs::event::{Events, ManualEventReader},
+    math::{Mat4, Vec3},
+    prelude::{error, info, warn, Assets, GlobalTransform, Handle, Without, World},
+    render::mesh::PrimitiveTopology,
+    window::WindowId,
 };
 use vulkano::{
-    buffer::{BufferUsage, CpuBufferPool, TypedBufferAccess},
+    buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer, CpuBufferPool, ImmutableBuffer},
     command_buffer::{
-        AutoCommandBufferBuilder, CommandBufferUsage, RenderPassBeginInfo, SubpassContents,
+        AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer,
+        RenderPassBeginInfo, SubpassContents,
     },
-    descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet},
-    device::{Device, DeviceCreateInfo, DeviceExtensions, Queue, QueueCreateInfo},
+    descriptor_set::{layout::DescriptorSetLayout, PersistentDescriptorSet, WriteDescriptorSet},
+    device::{Device, DeviceCreateInfo, DeviceExtensions, Features, Queue, QueueCreateInfo},
     format::Format,
-    image::{
-        view::ImageView, AttachmentImage, ImageDimensions, ImmutableImage, MipmapsCount,
-        SwapchainImage,
-    },
+    image::{view::ImageView, ImageDimensions, ImageViewAbstract, ImmutableImage, MipmapsCount},
     instance::{
-        debug::{
-            DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsMessenger,
-            DebugUtilsMessengerCreateInfo,
-        },
+        debug::{DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsMessenger},
         Instance, InstanceCreateInfo, InstanceExtensions,
     },
-    pipeline::{graphics::viewport::Viewport, GraphicsPipeline, Pipeline, PipelineBindPoint},
+    pipeline::{
+        cache::PipelineCache, graphics::viewport::Viewport, GraphicsPipeline, Pipeline,
+        PipelineBindPoint,
+    },
     render_pass::{Framebuffer, RenderPass},
-    sampler::{Filter, Sampler, SamplerCreateInfo},
+    sampler::{Sampler, SamplerCreateInfo, LOD_CLAMP_NONE},
     shader::ShaderModule,
-    swapchain::{self, Surface, Swapchain, SwapchainCreateInfo},
-    sync::GpuFuture,
+    swapchain::PresentMode,
+    sync::{self, GpuFuture},
 };
 use winit::{event_loop::ControlFlow, window::Window};
 
-use crate::{plugins::camera::ComputedProjection, shaders};
+use crate::{
+    data::{VertexLayout, DEFAULT_ATTRIBUTES},
+    plugins::camera::{ComputedProjection, InactiveCamera, RenderTarget},
+    shaders,
+};
 
+#[cfg(feature = "glsl")]
+use self::reload::ShaderReloader;
 use self::{
-    material::{DisplayMaterial, TextureImage},
-    mesh::DisplayMesh,
+    custom::{CustomMaterialRegistry, PassState, UsesCustomMaterial},
+    error::RendererError,
+    material::{AlphaMode, DisplayMaterial, SamplerSettings, TextureImage},
+    mesh::{DisplayMesh, IndexBuffer, PrimitiveStyle},
+    surface::WindowSurface,
+    util::{DeviceSelector, OffscreenTarget, PipelineKey},
 };
 
+pub mod cache;
+pub mod custom;
+pub mod debug;
+pub mod error;
 pub mod material;
 pub mod mesh;
+#[cfg(feature = "glsl")]
+pub mod reload;
+pub mod surface;
+pub mod upload;
 pub mod util;
 
 pub type WindowHandle = Arc<Window>;
 
+#[derive(Clone)]
+pub struct RendererSettings {
+    // Compile scene.vert/scene.frag from here at runtime and rebuild pipelines on change,
+    // needs the glsl feature
+    pub shader_dir: Option<PathBuf>,
+    // ALNYAN_VALIDATION in the environment takes precedence
+    pub validation: bool,
+    pub debug_severity: DebugUtilsMessageSeverity,
+    pub debug_types: DebugUtilsMessageType,
+    // ALNYAN_DEVICE in the environment takes precedence
+    pub device: DeviceSelector,
+}
+
 pub struct VulkanContext {
-    surface: Arc<Surface<WindowHandle>>,
+    _debug_messenger: Option<DebugUtilsMessenger>,
 
     device: Arc<Device>,
     queue: Arc<Queue>,
+    upload_queue: Arc<Queue>,
 
-    swapchain: Arc<Swapchain<WindowHandle>>,
-    swapchain_images: Vec<Arc<ImageView<SwapchainImage<WindowHandle>>>>,
-    viewport: Viewport,
-    need_swapchain_recreation: bool,
-    dimensions: [u32; 2],
+    surfaces: HashMap<WindowId, WindowSurface>,
+    format: Format,
 
     render_pass: Arc<RenderPass>,
     vs: Arc<ShaderModule>,
     fs: Arc<ShaderModule>,
-    pipeline: Arc<GraphicsPipeline>,
-    framebuffers: Vec<Arc<Framebuffer>>,
+    pipelines: HashMap<PipelineKey, Arc<GraphicsPipeline>>,
+    pipeline_cache: Arc<PipelineCache>,
+    #[cfg(feature = "glsl")]
+    shader_reloader: Option<ShaderReloader>,
     vp_pool: CpuBufferPool<shaders::vs::ty::ViewProjection_Data>,
     material_pool: CpuBufferPool<shaders::fs::ty::Material_Data>,
     model_pool: CpuBufferPool<shaders::vs::ty::Model_Data>,
-    color_view: Arc<ImageView<AttachmentImage>>,
-    depth_view: Arc<ImageView<AttachmentImage>>,
 
     dummy_texture: Arc<ImageView<ImmutableImage>>,
+    default_attributes: Arc<ImmutableBuffer<[[f32; 4]]>>,
+    samplers: Vec<(SamplerSettings, Arc<Sampler>)>,
+
+    offscreen_targets: HashMap<HandleId, OffscreenTarget>,
+
+    materials: HashMap<HandleId, MaterialEntry>,
+    material_events: ManualEventReader<AssetEvent<DisplayMaterial>>,
+
+    // Broken draws fail the same way every frame, each distinct error is only reported once
+    reported_errors: HashSet<String>,
+    pending_errors: Vec<RendererError>,
+}
+
+impl Default for RendererSettings {
+    fn default() -> Self {
+        Self {
+            shader_dir: None,
+            validation: false,
+            debug_severity: DebugUtilsMessageSeverity::errors_and_warnings(),
+            debug_types: DebugUtilsMessageType::all(),
+            device: DeviceSelector::Auto,
+        }
+    }
+}
+
+struct Draw<'a> {
+    transform: &'a GlobalTransform,
+    mesh: &'a DisplayMesh,
+    material: Option<&'a DisplayMaterial>,
+    shared: Option<HandleId>,
+    style: Option<&'a PrimitiveStyle>,
+}
+
+struct MaterialEntry {
+    buffer: Arc<CpuAccessibleBuffer<shaders::fs::ty::Material_Data>>,
+    texture: Arc<dyn ImageViewAbstract>,
     sampler: Arc<Sampler>,
+    set: Arc<PersistentDescriptorSet>,
+}
+
+struct CameraView {
+    view: Mat4,
+    projection: Mat4,
+    position: Vec3,
+}
+
+impl CameraView {
+    fn new(transform: &GlobalTransform, projection: &ComputedProjection) -> Self {
+        Self {
+            view: transform.compute_matrix().inverse(),
+            projection: *projection.transform_matrix(),
+            position: transform.translation,
+        }
+    }
+}
+
+fn material_data(material: Option<&DisplayMaterial>) -> shaders::fs::ty::Material_Data {
+    match material {
+        Some(material) => {
+            // Lighting happens in linear space, the sRGB swapchain encodes the result
+            let [s_r, s_g, s_b, _] = material.k_specular.as_linear_rgba_f32();
+            shaders::fs::ty::Material_Data {
+                k_diffuse: material.k_diffuse.as_linear_rgba_f32(),
+                k_specular: [s_r, s_g, s_b],
+                shininess: material.shininess,
+                alpha_cutoff: match material.alpha_mode {
+                    AlphaMode::Mask(cutoff) => cutoff,
+                    AlphaMode::Opaque | AlphaMode::Blend => 0.0,
+                },
+            }
+        }
+        None => shaders::fs::ty::Material_Data {
+            k_diffuse: [1.0, 0.0, 0.0, 1.0],
+            k_specular: [0.0; 3],
+            shininess: 1.0,
+            alpha_cutoff: 0.0,
+        },
+    }
+}
+
+fn clear_pass(framebuffer: Arc<Framebuffer>) -> RenderPassBeginInfo {
+    RenderPassBeginInfo {
+        clear_values: vec![
+            Some([0.0, 0.0, 0.0, 1.0].into()),
+            Some([0.0, 0.0, 0.0, 1.0].into()),
+            Some(1.0.into()),
+        ],
+        ..RenderPassBeginInfo::framebuffer(framebuffer)
+    }
+}
+
+fn bind_pipeline(
+    builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    pipeline: &Arc<GraphicsPipeline>,
+    vp_buffer: Arc<dyn BufferAccess>,
+) -> Result<(), RendererError> {
+    let vp_set = PersistentDescriptorSet::new(
+        pipeline.layout().set_layouts()[0].clone(),
+        vec![WriteDescriptorSet::buffer(0, vp_buffer)],
+    )?;
+
+    builder
+        .bind_pipeline_graphics(pipeline.clone())
+        .bind_descriptor_sets(
+            PipelineBindPoint::Graphics,
+            pipeline.layout().clone(),
+            0,
+            vp_set,
+        );
+
+    Ok(())
+}
+
+// Compares the views themselves, not the vtables of the trait objects
+fn same_view(a: &Arc<dyn ImageViewAbstract>, b: &Arc<dyn ImageViewAbstract>) -> bool {
+    std::ptr::eq(Arc::as_ptr(a) as *const u8, Arc::as_ptr(b) as *const u8)
 }
 
 impl VulkanContext {
-    pub fn new_windowed(window: WindowHandle) -> Self {
+    pub fn new_windowed(
+        window: WindowHandle,
+        settings: &RendererSettings,
+        vsync: bool,
+    ) -> Result<Self, RendererError> {
+        let debug_utils = InstanceExtensions::supported_by_core()
+            .map_or(false, |extensions| extensions.ext_debug_utils);
         let instance_extensions = vulkano_win::required_extensions().union(&InstanceExtensions {
-            ext_debug_utils: true,
+            ext_debug_utils: debug_utils,
             ..InstanceExtensions::none()
         });
-        let instance_layers = vec![
-            //"VK_LAYER_KHRONOS_validation".to_owned()
-        ];
+        let instance_layers = debug::validation_layers(debug::validation_enabled(settings));
         let device_extensions = DeviceExtensions {
             khr_swapchain: true,
             khr_maintenance1: true,
@@ -91,47 +245,40 @@ impl VulkanContext {
             enabled_extensions: instance_extensions,
             enabled_layers: instance_layers,
             ..Default::default()
-        })
-        .unwrap();
-
-        unsafe {
-            let _cb = DebugUtilsMessenger::new(
-                instance.clone(),
-                DebugUtilsMessengerCreateInfo {
-                    message_type: DebugUtilsMessageType::all(),
-                    message_severity: DebugUtilsMessageSeverity::all(),
-                    ..DebugUtilsMessengerCreateInfo::user_callback(Arc::new(|msg| {
-                        bevy::prelude::info!("{:?}", msg.description);
-                    }))
-                },
-            )
-            .ok();
-        }
+        })?;
 
-        let surface = vulkano_win::create_surface_from_winit(window, instance.clone()).unwrap();
-        let dimensions = surface.window().inner_size().into();
+        let debug_messenger = debug::create_messenger(instance.clone(), settings);
+
+        let surface = vulkano_win::create_surface_from_winit(window, instance.clone())?;
 
         let format = Format::B8G8R8A8_SRGB;
 
-        let (physical, queue_family) = util::select_physical_device(&instance, surface.clone());
+        let (physical, queue_family) =
+            util::select_physical_device(&instance, surface.clone(), &settings.device)?;
 
+        // A second queue of the same family takes uploads, blits for mip generation included
+        let queue_count = queue_family.queues_count().min(2);
         let (device, mut queues) = Device::new(
             physical,
             DeviceCreateInfo {
-                queue_create_infos: vec![QueueCreateInfo::family(queue_family)],
+                queue_create_infos: vec![QueueCreateInfo {
+                    queues: vec![0.5; queue_count],
+                    ..QueueCreateInfo::family(queue_family)
+                }],
                 enabled_extensions: physical
                     .supported_extensions()
                     .intersection(&device_extensions),
+                enabled_features: Features {
+                    sampler_anisotropy: physical.supported_features().sampler_anisotropy,
+                    wide_lines: physical.supported_features().wide_lines,
+                    large_points: physical.supported_features().large_points,
+                    ..Features::none()
+                },
                 ..Default::default()
             },
-        )
-        .unwrap();
+        )?;
         let queue = queues.next().unwrap();
-
-        let (swapchain, swapchain_images) =
-            util::create_swapchain(device.clone(), surface.clone(), format);
-
-        let viewport = util::create_viewport(dimensions);
+        let upload_queue = queues.next().unwrap_or_else(|| queue.clone());
 
         let render_pass = vulkano::single_pass_renderpass!(
             device.clone(),
@@ -160,8 +307,10 @@ impl VulkanContext {
                 depth_stencil: {depth},
                 resolve: [final_color]
             }
-        )
-        .unwrap();
+        )?;
+
+        let primary =
+            WindowSurface::new(surface, device.clone(), render_pass.clone(), format, vsync)?;
 
         let (dummy_texture, init) = {
             let image_data = [255u8, 255u8, 255u8, 255u8];
@@ -176,267 +325,788 @@ impl VulkanContext {
                 MipmapsCount::One,
                 Format::R8G8B8A8_UNORM,
                 queue.clone(),
-            )
-            .unwrap();
+            )?;
 
-            (ImageView::new_default(image).unwrap(), init)
+            (ImageView::new_default(image)?, init)
         };
 
-        let sampler = Sampler::new(
-            device.clone(),
-            SamplerCreateInfo {
-                mag_filter: Filter::Nearest,
-                min_filter: Filter::Nearest,
-                ..Default::default()
-            },
-        )
-        .unwrap();
+        let (default_attributes, default_attributes_init) = ImmutableBuffer::from_iter(
+            DEFAULT_ATTRIBUTES.map(|(_, _, value)| value),
+            BufferUsage::vertex_buffer(),
+            queue.clone(),
+        )?;
 
-        init.then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
-            .unwrap();
+        init.join(default_attributes_init)
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
 
-        let vs = shaders::vs::load(device.clone()).unwrap();
-        let fs = shaders::fs::load(device.clone()).unwrap();
-        let pipeline = util::create_pipeline(
-            render_pass.clone(),
-            vs.clone(),
-            fs.clone(),
-            viewport.clone(),
-            device.clone(),
-        );
-        let (framebuffers, color_view, depth_view) =
-            util::create_framebuffers(render_pass.clone(), device.clone(), &swapchain_images);
+        let pipeline_cache = cache::load_pipeline_cache(device.clone())?;
+
+        #[cfg(feature = "glsl")]
+        let shader_reloader = settings.shader_dir.as_ref().and_then(|dir| {
+            ShaderReloader::new(dir.clone())
+                .map_err(|err| error!("Could not watch shaders in {:?}: {}", dir, err))
+                .ok()
+        });
+        #[cfg(feature = "glsl")]
+        let runtime_shaders = shader_reloader.as_ref().and_then(|reloader| {
+            reloader
+                .load(device.clone())
+                .map_err(|err| error!("Falling back to the built-in shaders: {}", err))
+                .ok()
+        });
+        #[cfg(not(feature = "glsl"))]
+        let runtime_shaders = {
+            if settings.shader_dir.is_some() {
+                warn!("Built without the glsl feature, using the built-in shaders");
+            }
+            None
+        };
+
+        let (vs, fs) = match runtime_shaders {
+            Some(shaders) => shaders,
+            None => (
+                shaders::vs::load(device.clone())?,
+                shaders::fs::load(device.clone())?,
+            ),
+        };
 
         let vp_pool = CpuBufferPool::new(device.clone(), BufferUsage::uniform_buffer());
         let material_pool = CpuBufferPool::new(device.clone(), BufferUsage::uniform_buffer());
         let model_pool = CpuBufferPool::new(device.clone(), BufferUsage::uniform_buffer());
 
-        Self {
-            surface,
+        Ok(Self {
+            _debug_messenger: debug_messenger,
             device,
             queue,
-            swapchain,
-            swapchain_images,
-            viewport,
-            dimensions,
-            need_swapchain_recreation: false,
+            upload_queue,
+            surfaces: HashMap::from([(WindowId::primary(), primary)]),
+            format,
 
             render_pass,
-            pipeline,
+            pipelines: HashMap::new(),
+            pipeline_cache,
+            #[cfg(feature = "glsl")]
+            shader_reloader,
             vs,
             fs,
-            framebuffers,
             vp_pool,
             material_pool,
             model_pool,
-            depth_view,
-            color_view,
 
             dummy_texture,
-            sampler,
-        }
+            default_attributes,
+            samplers: Vec::new(),
+            offscreen_targets: HashMap::new(),
+
+            materials: HashMap::new(),
+            material_events: ManualEventReader::default(),
+
+            reported_errors: HashSet::new(),
+            pending_errors: Vec::new(),
+        })
     }
 
     pub const fn gfx_queue(&self) -> &Arc<Queue> {
         &self.queue
     }
 
-    pub fn invalidate_surface(&mut self) {
-        self.need_swapchain_recreation = true;
+    pub const fn upload_queue(&self) -> &Arc<Queue> {
+        &self.upload_queue
+    }
+
+    pub fn save_pipeline_cache(&self) {
+        cache::save_pipeline_cache(&self.device, &self.pipeline_cache);
+    }
+
+    // The window must be presentable by the queue the device was picked for
+    pub fn add_window(
+        &mut self,
+        id: WindowId,
+        window: WindowHandle,
+        vsync: bool,
+    ) -> Result<(), RendererError> {
+        let instance = self.device.instance().clone();
+        let surface = vulkano_win::create_surface_from_winit(window, instance)?;
+        if !self.queue.family().supports_surface(&surface)? {
+            return Err(RendererError::UnsupportedSurface);
+        }
+
+        let window_surface = WindowSurface::new(
+            surface,
+            self.device.clone(),
+            self.render_pass.clone(),
+            self.format,
+            vsync,
+        )?;
+        self.surfaces.insert(id, window_surface);
+
+        Ok(())
+    }
+
+    // The swapchain falls back to fifo when the preferred mode is not supported
+    pub fn present_mode(&self, id: WindowId) -> Option<PresentMode> {
+        self.surfaces
+            .get(&id)
+            .map(|surface| surface.swapchain().present_mode())
+    }
+
+    pub fn remove_window(&mut self, id: WindowId) {
+        self.surfaces.remove(&id);
     }
 
+    pub fn invalidate_surface(&mut self, id: WindowId) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.invalidate();
+        }
+    }
+
+    // Failed frames are skipped and reported, the next one starts from scratch
     pub fn do_frame(&mut self, _flow: &mut ControlFlow, world: &mut World) {
-        if self.need_swapchain_recreation {
-            self.recreate_swapchain();
+        match self.render_frame(world) {
+            Ok(()) => (),
+            // All windows are presented at once, there is no telling which one it was
+            Err(err) if err.is_out_of_date() => {
+                for surface in self.surfaces.values_mut() {
+                    surface.invalidate();
+                }
+            }
+            Err(err) => self.report_error(err),
         }
 
-        let (image_index, suboptimal, acquire_future) =
-            swapchain::acquire_next_image(self.swapchain.clone(), None).unwrap();
+        for err in self.pending_errors.drain(..) {
+            world.send_event(err);
+        }
+    }
 
-        if suboptimal {
-            self.need_swapchain_recreation = true;
+    fn report_error(&mut self, err: RendererError) {
+        if self.reported_errors.insert(err.to_string()) {
+            error!("Could not render: {}", err);
+            self.pending_errors.push(err);
         }
+    }
+
+    fn render_frame(&mut self, world: &mut World) -> Result<(), RendererError> {
+        #[cfg(feature = "glsl")]
+        self.reload_shaders();
+        self.invalidate_materials(world);
 
-        let (camera_transform, camera_projection) = match world
-            .query::<(&Transform, &ComputedProjection)>()
-            .get_single(world)
+        // The first active camera targeting a window draws into it
+        let mut window_cameras = HashMap::new();
+        for (transform, projection, target) in world
+            .query_filtered::<
+                (&GlobalTransform, &ComputedProjection, Option<&RenderTarget>),
+                Without<InactiveCamera>,
+            >()
+            .iter(world)
         {
-            Ok(e) => e,
-            Err(_) => {
-                acquire_future
-                    .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), image_index)
-                    .then_signal_fence_and_flush()
-                    .unwrap()
-                    .wait(None)
-                    .unwrap();
+            if let Some(id) = RenderTarget::window_of(target) {
+                window_cameras
+                    .entry(id)
+                    .or_insert_with(|| CameraView::new(transform, projection));
+            }
+        }
 
-                return;
+        let texture_cameras = world
+            .query_filtered::<
+                (&GlobalTransform, &ComputedProjection, &RenderTarget),
+                Without<InactiveCamera>,
+            >()
+            .iter(world)
+            .filter_map(|(transform, projection, target)| {
+                target
+                    .texture()
+                    .map(|handle| (CameraView::new(transform, projection), handle.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        self.offscreen_targets
+            .retain(|id, _| texture_cameras.iter().any(|(_, handle)| handle.id == *id));
+
+        let mut acquire_future = sync::now(self.device.clone()).boxed();
+        let mut frames = Vec::new();
+        for (id, camera) in window_cameras {
+            let surface = match self.surfaces.get_mut(&id) {
+                Some(surface) => surface,
+                None => continue,
+            };
+
+            // The other windows still get their frame
+            match surface.acquire() {
+                Ok(Some(image)) => {
+                    acquire_future = acquire_future.join(image.future).boxed();
+                    frames.push((id, image.index, camera));
+                }
+                Ok(None) => (),
+                Err(err) => self.report_error(err),
+            }
+        }
+
+        if frames.is_empty() {
+            return Ok(());
+        }
+
+        // Acquired images must be presented no matter what, the swapchain runs dry otherwise
+        let command_buffer = self
+            .record_frame(world, texture_cameras, &frames)
+            .or_else(|err| {
+                self.report_error(err);
+                self.record_cleared_frame(&frames)
+            });
+        let mut future = match command_buffer {
+            Ok(command_buffer) => acquire_future
+                .then_execute(self.queue.clone(), command_buffer)?
+                .boxed(),
+            Err(err) => {
+                self.report_error(err);
+                acquire_future
             }
         };
+        for (id, image_index, _) in frames {
+            let swapchain = self.surfaces[&id].swapchain().clone();
+            future = future
+                .then_swapchain_present(self.queue.clone(), swapchain, image_index)
+                .boxed();
+        }
 
-        let camera_position = camera_transform.translation;
-        let view = camera_transform.compute_matrix().inverse();
-        let projection = camera_projection.transform_matrix();
+        future.then_signal_fence_and_flush()?.wait(None)?;
 
-        let vp_buffer = {
-            let data = shaders::vs::ty::ViewProjection_Data {
-                camera_position: camera_position.into(),
-                view: view.to_cols_array_2d(),
-                projection: projection.to_cols_array_2d(),
+        Ok(())
+    }
+
+    fn record_frame(
+        &mut self,
+        world: &mut World,
+        texture_cameras: Vec<(CameraView, Handle<TextureImage>)>,
+        frames: &[(WindowId, usize, CameraView)],
+    ) -> Result<PrimaryAutoCommandBuffer, RendererError> {
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        // Texture targets go first so the window passes sample this frame's contents
+        for (camera, handle) in texture_cameras {
+            let (framebuffer, dimensions) = match self.prepare_offscreen_target(world, &handle) {
+                Ok(Some(target)) => target,
+                Ok(None) => continue,
+                Err(err) => {
+                    self.report_error(err);
+                    continue;
+                }
             };
 
-            self.vp_pool.next(data).unwrap()
-        };
+            self.record_pass(
+                &mut builder,
+                framebuffer,
+                util::create_viewport(dimensions),
+                &camera,
+                world,
+                Some(&handle),
+            )?;
+        }
 
-        let vp_set_layout = self.pipeline.layout().set_layouts().get(0).unwrap();
-        let material_set_layout = self.pipeline.layout().set_layouts().get(1).unwrap();
-        let model_set_layout = self.pipeline.layout().set_layouts().get(2).unwrap();
+        for (id, image_index, camera) in frames {
+            let surface = &self.surfaces[id];
+            let framebuffer = surface.framebuffer(*image_index);
+            let viewport = surface.viewport().clone();
 
-        let vp_set = PersistentDescriptorSet::new(
-            vp_set_layout.clone(),
-            vec![WriteDescriptorSet::buffer(0, vp_buffer)],
-        )
-        .unwrap();
+            self.record_pass(&mut builder, framebuffer, viewport, camera, world, None)?;
+        }
+
+        Ok(builder.build()?)
+    }
 
-        let framebuffer = &self.framebuffers[image_index];
+    // Stands in for a frame that could not be recorded
+    fn record_cleared_frame(
+        &self,
+        frames: &[(WindowId, usize, CameraView)],
+    ) -> Result<PrimaryAutoCommandBuffer, RendererError> {
         let mut builder = AutoCommandBufferBuilder::primary(
             self.device.clone(),
             self.queue.family(),
             CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        for (id, image_index, _) in frames {
+            let framebuffer = self.surfaces[id].framebuffer(*image_index);
+            builder
+                .begin_render_pass(clear_pass(framebuffer), SubpassContents::Inline)?
+                .end_render_pass()?;
+        }
+
+        Ok(builder.build()?)
+    }
+
+    fn prepare_offscreen_target(
+        &mut self,
+        world: &mut World,
+        handle: &Handle<TextureImage>,
+    ) -> Result<Option<(Arc<Framebuffer>, [u32; 2])>, RendererError> {
+        let dimensions: [u32; 2] = match world.resource::<Assets<TextureImage>>().get(handle) {
+            Some(texture) => texture.dimensions.into(),
+            None => return Ok(None),
+        };
+
+        let stale = self
+            .offscreen_targets
+            .get(&handle.id)
+            .map_or(true, |target| target.dimensions != dimensions);
+
+        if stale {
+            let target = util::create_offscreen_target(
+                self.render_pass.clone(),
+                self.device.clone(),
+                dimensions,
+                self.format,
+            )?;
+
+            if let Some(texture) = world
+                .resource_mut::<Assets<TextureImage>>()
+                .get_mut(handle)
+            {
+                texture.image = Some(target.view.clone());
+            }
+
+            self.offscreen_targets.insert(handle.id, target);
+        }
+
+        let target = &self.offscreen_targets[&handle.id];
+        Ok(Some((target.framebuffer.clone(), target.dimensions)))
+    }
+
+    fn sampler(&mut self, settings: &SamplerSettings) -> Result<Arc<Sampler>, RendererError> {
+        if let Some((_, sampler)) = self.samplers.iter().find(|(s, _)| s == settings) {
+            return Ok(sampler.clone());
+        }
+
+        let physical = self.device.physical_device();
+        let anisotropy = settings
+            .anisotropy
+            .filter(|_| self.device.enabled_features().sampler_anisotropy)
+            .map(|a| a.clamp(1.0, physical.properties().max_sampler_anisotropy));
+
+        let sampler = Sampler::new(
+            self.device.clone(),
+            SamplerCreateInfo {
+                mag_filter: settings.mag_filter,
+                min_filter: settings.min_filter,
+                mipmap_mode: settings.mipmap_mode,
+                address_mode: settings.address_mode,
+                mip_lod_bias: settings.lod_bias,
+                anisotropy,
+                lod: 0.0..=LOD_CLAMP_NONE,
+                ..Default::default()
+            },
+        )?;
+
+        self.samplers.push((*settings, sampler.clone()));
+        Ok(sampler)
+    }
+
+    // A broken shader leaves the previous modules and pipelines in place
+    #[cfg(feature = "glsl")]
+    fn reload_shaders(&mut self) {
+        let reloader = match &self.shader_reloader {
+            Some(reloader) if reloader.changed() => reloader,
+            _ => return,
+        };
+
+        let (vs, fs) = match reloader.load(self.device.clone()) {
+            Ok(shaders) => shaders,
+            Err(err) => {
+                error!("Keeping the previous shaders: {}", err);
+                return;
+            }
+        };
+
+        let pipelines = self
+            .pipelines
+            .keys()
+            .map(|key| {
+                util::create_pipeline(
+                    self.render_pass.clone(),
+                    vs.clone(),
+                    fs.clone(),
+                    key,
+                    self.pipeline_cache.clone(),
+                    self.device.clone(),
+                )
+                .map(|pipeline| (key.clone(), pipeline))
+            })
+            .collect::<Result<HashMap<_, _>, _>>();
+
+        match pipelines {
+            Ok(pipelines) => {
+                info!("Reloaded the scene shaders");
+                self.vs = vs;
+                self.fs = fs;
+                self.pipelines = pipelines;
+            }
+            Err(err) => error!("Keeping the previous shaders: {}", err),
+        }
+    }
+
+    fn pipeline(
+        &mut self,
+        layout: &VertexLayout,
+        topology: PrimitiveTopology,
+        blend: bool,
+    ) -> Result<Arc<GraphicsPipeline>, RendererError> {
+        let key = PipelineKey {
+            layout: layout.clone(),
+            topology,
+            blend,
+        };
+        if let Some(pipeline) = self.pipelines.get(&key) {
+            return Ok(pipeline.clone());
+        }
+
+        let pipeline = util::create_pipeline(
+            self.render_pass.clone(),
+            self.vs.clone(),
+            self.fs.clone(),
+            &key,
+            self.pipeline_cache.clone(),
+            self.device.clone(),
+        )?;
+        self.pipelines.insert(key, pipeline.clone());
+        Ok(pipeline)
+    }
+
+    fn clamp_style(&self, style: PrimitiveStyle) -> PrimitiveStyle {
+        let features = self.device.enabled_features();
+        let properties = self.device.physical_device().properties();
+
+        let point_size = if features.large_points {
+            let [min, max] = properties.point_size_range;
+            style.point_size.clamp(min, max)
+        } else {
+            1.0
+        };
+        let line_width = if features.wide_lines {
+            let [min, max] = properties.line_width_range;
+            style.line_width.clamp(min, max)
+        } else {
+            1.0
+        };
+
+        PrimitiveStyle {
+            point_size,
+            line_width,
+        }
+    }
+
+    fn texture_binding(
+        &self,
+        handle: Option<&Handle<TextureImage>>,
+        sampler: Option<SamplerSettings>,
+        textures: &Assets<TextureImage>,
+        target: Option<&Handle<TextureImage>>,
+    ) -> (Arc<dyn ImageViewAbstract>, SamplerSettings) {
+        let dummy: Arc<dyn ImageViewAbstract> = self.dummy_texture.clone();
+
+        // A target can't be sampled while it is being rendered into
+        handle
+            .filter(|&handle| Some(handle) != target)
+            .and_then(|handle| textures.get(handle))
+            .and_then(|texture| Some((texture.image.clone()?, sampler.unwrap_or(texture.sampler))))
+            .unwrap_or((dummy, SamplerSettings::default()))
+    }
+
+    fn material_texture(
+        &self,
+        material: Option<&DisplayMaterial>,
+        world: &World,
+        target: Option<&Handle<TextureImage>>,
+    ) -> (Arc<dyn ImageViewAbstract>, SamplerSettings) {
+        self.texture_binding(
+            material.and_then(|material| material.k_diffuse_map.as_ref()),
+            material.and_then(|material| material.k_diffuse_sampler),
+            world.resource::<Assets<TextureImage>>(),
+            target,
         )
-        .unwrap();
+    }
 
-        let render_pass_begin_info = RenderPassBeginInfo {
-            clear_values: vec![
-                Some([0.0, 0.0, 0.0, 1.0].into()),
-                Some([0.0, 0.0, 0.0, 1.0].into()),
-                Some(1.0.into()),
-            ],
-            ..RenderPassBeginInfo::framebuffer(framebuffer.clone())
+    fn draw_mesh(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline: &Arc<GraphicsPipeline>,
+        material_set: Arc<PersistentDescriptorSet>,
+        transform: &GlobalTransform,
+        mesh: &DisplayMesh,
+        style: Option<&PrimitiveStyle>,
+    ) -> Result<(), RendererError> {
+        let style = self.clamp_style(style.copied().unwrap_or_default());
+        let model_buffer = {
+            let data = shaders::vs::ty::Model_Data {
+                model: transform.compute_matrix().to_cols_array_2d(),
+                point_size: style.point_size,
+            };
+
+            self.model_pool.next(data)?
         };
 
+        let model_set = PersistentDescriptorSet::new(
+            pipeline.layout().set_layouts()[2].clone(),
+            vec![WriteDescriptorSet::buffer(0, model_buffer)],
+        )?;
+
+        let mut vertex_buffers = mesh.vertex_buffers();
+        vertex_buffers.push(self.default_attributes.clone());
+
         builder
-            .begin_render_pass(render_pass_begin_info, SubpassContents::Inline)
-            .unwrap()
-            .bind_pipeline_graphics(self.pipeline.clone())
             .bind_descriptor_sets(
                 PipelineBindPoint::Graphics,
-                self.pipeline.layout().clone(),
-                0,
-                vp_set,
-            );
+                pipeline.layout().clone(),
+                1,
+                (material_set, model_set),
+            )
+            .bind_vertex_buffers(0, vertex_buffers);
 
-        let mut query = world.query::<(&Transform, &DisplayMesh, Option<&DisplayMaterial>)>();
-        for (transform, mesh, material) in query.iter(world) {
-            let model_matrix: Mat4 = transform.compute_matrix();
-
-            let texture;
-            let textures = world.resource::<Assets<TextureImage>>();
-            let material_buffer = {
-                let data;
-
-                if let Some(material) = material {
-                    data = shaders::fs::ty::Material_Data {
-                        k_diffuse: material.k_diffuse.as_rgba_f32(),
-                    };
-
-                    if let Some(k_diffuse_map) = material
-                        .k_diffuse_map
-                        .as_ref()
-                        .and_then(|handle| textures.get(handle))
-                        .and_then(|image| image.image.clone())
-                    {
-                        texture = k_diffuse_map;
-                    } else {
-                        texture = self.dummy_texture.clone();
-                    }
-                } else {
-                    texture = self.dummy_texture.clone();
-                    data = shaders::fs::ty::Material_Data {
-                        k_diffuse: [1.0, 0.0, 0.0, 1.0],
-                    };
+        if util::is_line_topology(mesh.topology()) {
+            builder.set_line_width(style.line_width);
+        }
+
+        match mesh.indices() {
+            IndexBuffer::U16(indices) => builder.bind_index_buffer(indices.clone()),
+            IndexBuffer::U32(indices) => builder.bind_index_buffer(indices.clone()),
+        };
+
+        builder.draw_indexed(mesh.indices().count(), 1, 0, 0, 0)?;
+
+        Ok(())
+    }
+
+    fn shared_material_set(
+        &mut self,
+        id: HandleId,
+        material: &DisplayMaterial,
+        texture: Arc<dyn ImageViewAbstract>,
+        sampler: Arc<Sampler>,
+        set_layout: &Arc<DescriptorSetLayout>,
+    ) -> Result<Arc<PersistentDescriptorSet>, RendererError> {
+        if let Some(entry) = self.materials.get(&id) {
+            if same_view(&entry.texture, &texture) && Arc::ptr_eq(&entry.sampler, &sampler) {
+                return Ok(entry.set.clone());
+            }
+        }
+
+        let buffer = match self.materials.get(&id) {
+            Some(entry) => entry.buffer.clone(),
+            None => CpuAccessibleBuffer::from_data(
+                self.device.clone(),
+                BufferUsage::uniform_buffer(),
+                false,
+                material_data(Some(material)),
+            )?,
+        };
+        let set = PersistentDescriptorSet::new(
+            set_layout.clone(),
+            vec![
+                WriteDescriptorSet::buffer(0, buffer.clone()),
+                WriteDescriptorSet::image_view_sampler(1, texture.clone(), sampler.clone()),
+            ],
+        )?;
+
+        self.materials.insert(
+            id,
+            MaterialEntry {
+                buffer,
+                texture,
+                sampler,
+                set: set.clone(),
+            },
+        );
+        Ok(set)
+    }
+
+    // Edited or dropped material assets lose their GPU copy and get rebuilt on next use
+    fn invalidate_materials(&mut self, world: &World) {
+        let events = world.resource::<Events<AssetEvent<DisplayMaterial>>>();
+
+        for event in self.material_events.iter(events) {
+            match event {
+                AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+                    self.materials.remove(&handle.id);
                 }
+                AssetEvent::Created { .. } => (),
+            }
+        }
+    }
 
-                self.material_pool.next(data).unwrap()
+    fn record_pass(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        framebuffer: Arc<Framebuffer>,
+        viewport: Viewport,
+        camera: &CameraView,
+        world: &mut World,
+        target: Option<&Handle<TextureImage>>,
+    ) -> Result<(), RendererError> {
+        let vp_buffer: Arc<dyn BufferAccess> = {
+            let data = shaders::vs::ty::ViewProjection_Data {
+                camera_position: camera.position.into(),
+                view: camera.view.to_cols_array_2d(),
+                projection: camera.projection.to_cols_array_2d(),
             };
-            let model_buffer = {
-                let data = shaders::vs::ty::Model_Data {
-                    model: model_matrix.to_cols_array_2d(),
-                };
 
-                self.model_pool.next(data).unwrap()
+            self.vp_pool.next(data)?
+        };
+
+        builder
+            .begin_render_pass(clear_pass(framebuffer), SubpassContents::Inline)?
+            .set_viewport(0, [viewport]);
+
+        // Custom materials go first so blended geometry ends up on top of them
+        if let Some(mut registry) = world.remove_resource::<CustomMaterialRegistry>() {
+            let pass = PassState {
+                vp_buffer: vp_buffer.clone(),
+                target,
             };
+            registry.draw(self, builder, &pass, world);
+            world.insert_resource(registry);
+        }
 
-            let material_set = PersistentDescriptorSet::new(
-                material_set_layout.clone(),
-                vec![
-                    WriteDescriptorSet::buffer(0, material_buffer),
-                    WriteDescriptorSet::image_view_sampler(1, texture, self.sampler.clone()),
-                ],
-            )
-            .unwrap();
-            let model_set = PersistentDescriptorSet::new(
-                model_set_layout.clone(),
-                vec![WriteDescriptorSet::buffer(0, model_buffer)],
-            )
-            .unwrap();
+        let mut query = world.query_filtered::<(
+            &GlobalTransform,
+            &DisplayMesh,
+            Option<&DisplayMaterial>,
+            Option<&Handle<DisplayMaterial>>,
+            Option<&PrimitiveStyle>,
+        ), Without<UsesCustomMaterial>>();
+        let materials = world.resource::<Assets<DisplayMaterial>>();
 
-            builder
-                .bind_descriptor_sets(
-                    PipelineBindPoint::Graphics,
-                    self.pipeline.layout().clone(),
-                    1,
-                    (material_set, model_set),
-                )
-                .bind_vertex_buffers(0, mesh.vertices().clone())
-                .bind_index_buffer(mesh.indices().clone())
-                .draw_indexed(mesh.indices().len() as u32, 1, 0, 0, 0)
-                .unwrap();
+        let mut batches = HashMap::<_, Vec<_>>::new();
+        let mut blended = Vec::new();
+        for (transform, mesh, material, material_handle, style) in query.iter(world) {
+            // A per-entity material wins over a shared one
+            let shared = match (material, material_handle) {
+                (Some(_), _) | (None, None) => None,
+                (None, Some(handle)) => match materials.get(handle) {
+                    Some(material) => Some((handle.id, material)),
+                    None => continue,
+                },
+            };
+            let material = material.or(shared.map(|(_, material)| material));
+            let blend = material.map_or(false, |material| material.alpha_mode == AlphaMode::Blend);
+
+            let key = (mesh.layout(), mesh.topology(), blend);
+            let draw = Draw {
+                transform,
+                mesh,
+                material,
+                shared: shared.map(|(id, _)| id),
+                style,
+            };
+
+            if blend {
+                blended.push((key, draw));
+            } else {
+                batches.entry(key).or_default().push(draw);
+            }
         }
-        builder.end_render_pass().unwrap();
 
-        let future = acquire_future
-            .then_execute(self.queue.clone(), builder.build().unwrap())
-            .unwrap()
-            .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), image_index)
-            .then_signal_fence_and_flush()
-            .unwrap();
+        // Blended geometry goes last, farthest first
+        blended.sort_by(|(_, a), (_, b)| {
+            let a = a.transform.translation.distance_squared(camera.position);
+            let b = b.transform.translation.distance_squared(camera.position);
+            b.total_cmp(&a)
+        });
 
-        future.wait(None).unwrap();
+        let mut batches = batches.into_iter().collect::<Vec<_>>();
+        for (key, draw) in blended {
+            match batches.last_mut() {
+                Some((last, draws)) if *last == key => draws.push(draw),
+                _ => batches.push((key, vec![draw])),
+            }
+        }
+
+        for ((layout, topology, blend), draws) in batches {
+            let pipeline = self.pipeline(layout, topology, blend);
+            self.record_batch(
+                builder,
+                pipeline,
+                vp_buffer.clone(),
+                draws,
+                |renderer, builder, pipeline, draw| {
+                    renderer.record_draw(builder, pipeline, &draw, world, target)
+                },
+            );
+        }
+        builder.end_render_pass()?;
+
+        Ok(())
     }
 
-    fn recreate_swapchain(&mut self) {
-        self.dimensions = self.surface.window().inner_size().into();
-        let (new_swapchain, new_images) = self
-            .swapchain
-            .recreate(SwapchainCreateInfo {
-                image_extent: self.dimensions,
-                ..self.swapchain.create_info()
-            })
-            .unwrap();
+    // A broken batch or draw is left out, the rest of the pass still goes through
+    fn record_batch<T>(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline: Result<Arc<GraphicsPipeline>, RendererError>,
+        vp_buffer: Arc<dyn BufferAccess>,
+        items: Vec<T>,
+        mut draw: impl FnMut(
+            &mut Self,
+            &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+            &Arc<GraphicsPipeline>,
+            T,
+        ) -> Result<(), RendererError>,
+    ) {
+        let bound = pipeline.and_then(|pipeline| {
+            bind_pipeline(builder, &pipeline, vp_buffer)?;
+            Ok(pipeline)
+        });
+        let pipeline = match bound {
+            Ok(pipeline) => pipeline,
+            Err(err) => return self.report_error(err),
+        };
 
-        self.swapchain = new_swapchain;
-        self.swapchain_images = new_images
-            .into_iter()
-            .map(ImageView::new_default)
-            .collect::<Result<_, _>>()
-            .unwrap();
+        for item in items {
+            if let Err(err) = draw(self, builder, &pipeline, item) {
+                self.report_error(err);
+            }
+        }
+    }
 
-        self.viewport = util::create_viewport(self.dimensions);
+    fn record_draw(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline: &Arc<GraphicsPipeline>,
+        draw: &Draw,
+        world: &World,
+        target: Option<&Handle<TextureImage>>,
+    ) -> Result<(), RendererError> {
+        let material_set_layout = &pipeline.layout().set_layouts()[1];
+        let material = draw.material;
+        let (texture, sampler_settings) = self.material_texture(material, world, target);
+        let sampler = self.sampler(&sampler_settings)?;
 
-        self.pipeline = util::create_pipeline(
-            self.render_pass.clone(),
-            self.vs.clone(),
-            self.fs.clone(),
-            self.viewport.clone(),
-            self.device.clone(),
-        );
-        (self.framebuffers, self.color_view, self.depth_view) = util::create_framebuffers(
-            self.render_pass.clone(),
-            self.device.clone(),
-            &self.swapchain_images,
-        );
+        let material_set = match (draw.shared, material) {
+            (Some(id), Some(material)) => {
+                self.shared_material_set(id, material, texture, sampler, material_set_layout)?
+            }
+            _ => {
+                let material_buffer = self.material_pool.next(material_data(material))?;
+
+                PersistentDescriptorSet::new(
+                    material_set_layout.clone(),
+                    vec![
+                        WriteDescriptorSet::buffer(0, material_buffer),
+                        WriteDescriptorSet::image_view_sampler(1, texture, sampler),
+                    ],
+                )?
+            }
+        };
+
+        self.draw_mesh(
+            builder,
+            pipeline,
+            material_set,
+            draw.transform,
+            draw.mesh,
+            draw.style,
+        )
     }
 }
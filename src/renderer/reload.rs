@@ -0,0 +1,81 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+};
+
+use bevy::prelude::warn;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use vulkano::{device::Device, shader::ShaderModule};
+
+use super::custom::{check_interface, load_shader, ShaderError, ShaderSource, ShaderStage};
+
+pub struct ShaderReloader {
+    dir: PathBuf,
+    events: Receiver<notify::Result<Event>>,
+    _watcher: RecommendedWatcher,
+}
+
+impl ShaderReloader {
+    pub fn new(dir: PathBuf) -> notify::Result<Self> {
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            dir,
+            events,
+            _watcher: watcher,
+        })
+    }
+
+    // Drains everything the watcher reported since the last call
+    pub fn changed(&self) -> bool {
+        self.events
+            .try_iter()
+            .fold(false, |changed, event| match event {
+                Ok(event) => {
+                    let touched = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                        && event.paths.iter().any(|path| is_shader(path));
+                    changed || touched
+                }
+                Err(err) => {
+                    warn!("Shader watcher error: {}", err);
+                    changed
+                }
+            })
+    }
+
+    // The sources must keep the interface of the built-in shaders, their uniform types are
+    // still generated at build time
+    pub fn load(
+        &self,
+        device: Arc<Device>,
+    ) -> Result<(Arc<ShaderModule>, Arc<ShaderModule>), ShaderError> {
+        let vs = self.load_stage(device.clone(), "scene.vert", ShaderStage::Vertex)?;
+        let fs = self.load_stage(device, "scene.frag", ShaderStage::Fragment)?;
+        check_interface(&vs, &fs)?;
+
+        Ok((vs, fs))
+    }
+
+    fn load_stage(
+        &self,
+        device: Arc<Device>,
+        name: &str,
+        stage: ShaderStage,
+    ) -> Result<Arc<ShaderModule>, ShaderError> {
+        let source = std::fs::read_to_string(self.dir.join(name)).map_err(ShaderError::Io)?;
+        // Safety: GLSL is compiled by shaderc, which only produces valid SPIR-V
+        unsafe { load_shader(device, &ShaderSource::Glsl(source.into()), stage, name) }
+    }
+}
+
+fn is_shader(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("vert" | "frag" | "glsl")
+    )
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use vulkano::{
+    device::{Device, DeviceOwned},
+    format::Format,
+    image::{view::ImageView, SwapchainImage},
+    pipeline::graphics::viewport::Viewport,
+    render_pass::{Framebuffer, RenderPass},
+    swapchain::{self, Surface, Swapchain, SwapchainAcquireFuture, SwapchainCreateInfo},
+};
+
+use super::{error::RendererError, util, WindowHandle};
+
+// Everything tied to one window, the device, pipelines and resources are shared between them
+pub struct WindowSurface {
+    surface: Arc<Surface<WindowHandle>>,
+    render_pass: Arc<RenderPass>,
+
+    swapchain: Arc<Swapchain<WindowHandle>>,
+    swapchain_images: Vec<Arc<ImageView<SwapchainImage<WindowHandle>>>>,
+    viewport: Viewport,
+    need_swapchain_recreation: bool,
+    dimensions: [u32; 2],
+
+    // They hold on to the multisampled attachments as well
+    framebuffers: Vec<Arc<Framebuffer>>,
+}
+
+pub struct AcquiredImage {
+    pub index: usize,
+    pub future: SwapchainAcquireFuture<WindowHandle>,
+}
+
+impl WindowSurface {
+    pub fn new(
+        surface: Arc<Surface<WindowHandle>>,
+        device: Arc<Device>,
+        render_pass: Arc<RenderPass>,
+        format: Format,
+        vsync: bool,
+    ) -> Result<Self, RendererError> {
+        let dimensions = surface.window().inner_size().into();
+        let (swapchain, swapchain_images) =
+            util::create_swapchain(device.clone(), surface.clone(), format, vsync)?;
+        let (framebuffers, _, _) =
+            util::create_framebuffers(render_pass.clone(), device, &swapchain_images)?;
+
+        Ok(Self {
+            surface,
+            render_pass,
+            swapchain,
+            swapchain_images,
+            viewport: util::create_viewport(dimensions),
+            need_swapchain_recreation: false,
+            dimensions,
+            framebuffers,
+        })
+    }
+
+    pub const fn swapchain(&self) -> &Arc<Swapchain<WindowHandle>> {
+        &self.swapchain
+    }
+
+    pub const fn viewport(&self) -> &Viewport {
+        &self.viewport
+    }
+
+    pub fn framebuffer(&self, image_index: usize) -> Arc<Framebuffer> {
+        self.framebuffers[image_index].clone()
+    }
+
+    pub fn invalidate(&mut self) {
+        self.need_swapchain_recreation = true;
+    }
+
+    // None when the surface is out of date or minimized, it is retried on the next frame
+    pub fn acquire(&mut self) -> Result<Option<AcquiredImage>, RendererError> {
+        // A minimized window has no area to present to, the swapchain is recreated once it is
+        // restored
+        let size = self.surface.window().inner_size();
+        if size.width == 0 || size.height == 0 {
+            self.need_swapchain_recreation = true;
+            return Ok(None);
+        }
+
+        let result = self.recreate_if_needed().and_then(|_| {
+            swapchain::acquire_next_image(self.swapchain.clone(), None).map_err(RendererError::from)
+        });
+
+        match result {
+            Ok((index, suboptimal, future)) => {
+                if suboptimal {
+                    self.need_swapchain_recreation = true;
+                }
+
+                Ok(Some(AcquiredImage { index, future }))
+            }
+            Err(err) if err.is_out_of_date() => {
+                self.need_swapchain_recreation = true;
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn recreate_if_needed(&mut self) -> Result<(), RendererError> {
+        if !self.need_swapchain_recreation {
+            return Ok(());
+        }
+
+        self.dimensions = self.surface.window().inner_size().into();
+        let (new_swapchain, new_images) = self.swapchain.recreate(SwapchainCreateInfo {
+            image_extent: self.dimensions,
+            ..self.swapchain.create_info()
+        })?;
+
+        self.swapchain = new_swapchain;
+        self.swapchain_images = new_images
+            .into_iter()
+            .map(ImageView::new_default)
+            .collect::<Result<_, _>>()?;
+        self.need_swapchain_recreation = false;
+
+        self.viewport = util::create_viewport(self.dimensions);
+
+        (self.framebuffers, _, _) = util::create_framebuffers(
+            self.render_pass.clone(),
+            self.swapchain.device().clone(),
+            &self.swapchain_images,
+        )?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use bevy::{
+    asset::HandleId,
+    prelude::{error, Component, Entity},
+};
+use vulkano::{
+    device::Queue,
+    image::ImageViewAbstract,
+    sync::{FenceSignalFuture, FlushError, GpuFuture},
+};
+
+use super::mesh::DisplayMesh;
+
+#[derive(Component)]
+pub struct PendingMesh(pub u64);
+
+pub enum UploadTarget {
+    Texture {
+        id: HandleId,
+        ticket: u64,
+        view: Arc<dyn ImageViewAbstract>,
+    },
+    Mesh {
+        entity: Entity,
+        ticket: u64,
+        mesh: DisplayMesh,
+    },
+}
+
+#[derive(Default)]
+pub struct UploadBatch {
+    future: Option<Box<dyn GpuFuture + Send>>,
+    targets: Vec<UploadTarget>,
+}
+
+struct InFlightBatch {
+    future: FenceSignalFuture<Box<dyn GpuFuture + Send>>,
+    targets: Vec<UploadTarget>,
+}
+
+pub struct UploadQueue {
+    queue: Arc<Queue>,
+    next_ticket: u64,
+    in_flight: Vec<InFlightBatch>,
+    failed: Vec<(FlushError, Vec<UploadTarget>)>,
+}
+
+impl UploadBatch {
+    pub fn add<F: GpuFuture + Send + 'static>(&mut self, future: F, target: UploadTarget) {
+        self.future = Some(match self.future.take() {
+            Some(previous) => Box::new(previous.join(future)),
+            None => Box::new(future),
+        });
+        self.targets.push(target);
+    }
+}
+
+impl UploadQueue {
+    pub fn new(queue: Arc<Queue>) -> Self {
+        Self {
+            queue,
+            next_ticket: 0,
+            in_flight: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+
+    pub const fn queue(&self) -> &Arc<Queue> {
+        &self.queue
+    }
+
+    pub fn next_ticket(&mut self) -> u64 {
+        self.next_ticket += 1;
+        self.next_ticket
+    }
+
+    pub fn submit(&mut self, batch: UploadBatch) {
+        let future = match batch.future {
+            Some(future) => future,
+            None => return,
+        };
+
+        match future.then_signal_fence_and_flush() {
+            Ok(future) => self.in_flight.push(InFlightBatch {
+                future,
+                targets: batch.targets,
+            }),
+            Err(err) => {
+                error!("Could not submit {} uploads: {}", batch.targets.len(), err);
+                self.failed.push((err, batch.targets));
+            }
+        }
+    }
+
+    // Targets of batches that never reached the GPU, they have to be marked as failed
+    pub fn take_failed(&mut self) -> Vec<(FlushError, Vec<UploadTarget>)> {
+        std::mem::take(&mut self.failed)
+    }
+
+    pub fn take_completed(&mut self) -> Vec<UploadTarget> {
+        let mut completed = Vec::new();
+        let mut i = 0;
+
+        while i < self.in_flight.len() {
+            if self.in_flight[i].future.is_signaled().unwrap_or(false) {
+                let InFlightBatch { future, targets } = self.in_flight.swap_remove(i);
+                // Dropping the signaled future releases the resources for use on other queues
+                drop(future);
+                completed.extend(targets);
+            } else {
+                i += 1;
+            }
+        }
+
+        completed
+    }
+}
@@ -1,5 +1,9 @@
-use std::sync::Arc;
+use std::{str::FromStr, sync::Arc};
 
+use bevy::{
+    prelude::{info, warn},
+    render::mesh::PrimitiveTopology as MeshTopology,
+};
 use vulkano::{
     device::{
         physical::{PhysicalDevice, PhysicalDeviceType, QueueFamily},
@@ -12,25 +16,32 @@ use vulkano::{
     },
     instance::Instance,
     pipeline::{
+        cache::PipelineCache,
         graphics::{
-            depth_stencil::DepthStencilState,
-            input_assembly::InputAssemblyState,
+            color_blend::ColorBlendState,
+            depth_stencil::{CompareOp, DepthState, DepthStencilState},
+            input_assembly::{InputAssemblyState, PrimitiveTopology},
             multisample::MultisampleState,
-            vertex_input::BuffersDefinition,
+            rasterization::RasterizationState,
+            vertex_input::{
+                VertexInputAttributeDescription, VertexInputBindingDescription, VertexInputRate,
+                VertexInputState,
+            },
             viewport::{Viewport, ViewportState},
+            GraphicsPipelineCreationError,
         },
-        GraphicsPipeline,
+        GraphicsPipeline, StateMode,
     },
     render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass},
     shader::ShaderModule,
-    swapchain::{Surface, Swapchain, SwapchainCreateInfo},
+    swapchain::{PresentMode, Surface, Swapchain, SwapchainCreateInfo},
 };
 use vulkano_win::SafeBorrow;
 use winit::window::Window;
 
-use crate::data::Vertex;
+use crate::data::{VertexLayout, DEFAULT_ATTRIBUTES};
 
-use super::WindowHandle;
+use super::{error::RendererError, WindowHandle};
 
 pub type SwapchainCreateOutput = (
     Arc<Swapchain<WindowHandle>>,
@@ -43,38 +54,191 @@ pub type FramebufferCreateOutput = (
     Arc<ImageView<AttachmentImage>>,
 );
 
+type AttachmentViews = (Arc<ImageView<AttachmentImage>>, Arc<ImageView<AttachmentImage>>);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub layout: VertexLayout,
+    pub topology: MeshTopology,
+    pub blend: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum DeviceSelector {
+    Auto,
+    Name(String),
+    Index(usize),
+    Vendor(u32),
+}
+
+pub struct OffscreenTarget {
+    pub framebuffer: Arc<Framebuffer>,
+    pub view: Arc<ImageView<AttachmentImage>>,
+    pub dimensions: [u32; 2],
+}
+
+const DEVICE_ENV: &str = "ALNYAN_DEVICE";
+
+// Vendor names understood by the selector, as registered with Khronos/PCI-SIG
+const VENDORS: &[(&str, u32)] = &[
+    ("amd", 0x1002),
+    ("arm", 0x13b5),
+    ("intel", 0x8086),
+    ("mesa", 0x10005),
+    ("nvidia", 0x10de),
+    ("qualcomm", 0x5143),
+];
+
+fn device_rank(device: &PhysicalDevice) -> u32 {
+    match device.properties().device_type {
+        PhysicalDeviceType::DiscreteGpu => 0,
+        PhysicalDeviceType::IntegratedGpu => 1,
+        PhysicalDeviceType::VirtualGpu => 2,
+        PhysicalDeviceType::Cpu => 3,
+        _ => 4,
+    }
+}
+
+impl DeviceSelector {
+    fn matches(&self, device: &PhysicalDevice) -> bool {
+        let properties = device.properties();
+
+        match self {
+            Self::Auto => true,
+            Self::Name(name) => properties
+                .device_name
+                .to_lowercase()
+                .contains(&name.to_lowercase()),
+            Self::Index(index) => device.index() == *index,
+            Self::Vendor(vendor) => properties.vendor_id == *vendor,
+        }
+    }
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+// Plain numbers are indices, known vendor names and "vendor:<id>" select a vendor, anything
+// else is a part of the device name
+impl FromStr for DeviceSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+
+        if value.is_empty() || value.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+        if let Ok(index) = value.parse() {
+            return Ok(Self::Index(index));
+        }
+        if let Some(&(_, id)) = VENDORS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(value))
+        {
+            return Ok(Self::Vendor(id));
+        }
+        if let Some(id) = value.strip_prefix("vendor:") {
+            let id = id.trim_start_matches("0x");
+            if let Ok(id) = u32::from_str_radix(id, 16) {
+                return Ok(Self::Vendor(id));
+            }
+        }
+
+        Ok(Self::Name(value.to_owned()))
+    }
+}
+
 pub fn select_physical_device<T: SafeBorrow<Window>>(
     instance: &Arc<Instance>,
     surface: Arc<Surface<T>>,
-) -> (PhysicalDevice, QueueFamily) {
-    PhysicalDevice::enumerate(instance)
-        .filter_map(|p| {
-            p.queue_families()
-                .find(|&q| q.supports_graphics() && q.supports_surface(&surface).unwrap_or(false))
-                .map(|q| (p, q))
-        })
-        .min_by_key(|(p, _)| match p.properties().device_type {
-            PhysicalDeviceType::DiscreteGpu => 0,
-            PhysicalDeviceType::IntegratedGpu => 1,
-            PhysicalDeviceType::VirtualGpu => 2,
-            PhysicalDeviceType::Cpu => 3,
-            _ => 4,
-        })
-        .unwrap()
+    selector: &DeviceSelector,
+) -> Result<(PhysicalDevice, QueueFamily), RendererError> {
+    let selector = match std::env::var(DEVICE_ENV) {
+        Ok(value) => value.parse().unwrap_or_default(),
+        Err(_) => selector.clone(),
+    };
+    let mut candidates = Vec::new();
+
+    for device in PhysicalDevice::enumerate(instance) {
+        let properties = device.properties();
+        let family = device
+            .queue_families()
+            .find(|&q| q.supports_graphics() && q.supports_surface(&surface).unwrap_or(false));
+
+        info!(
+            "Device #{}: {} ({:?}, vendor {:#06x}, Vulkan {}, driver {}){}",
+            device.index(),
+            properties.device_name,
+            properties.device_type,
+            properties.vendor_id,
+            device.api_version(),
+            properties.driver_info.as_deref().unwrap_or("unknown"),
+            if family.is_some() { "" } else { ", can't present" }
+        );
+
+        if let Some(family) = family {
+            candidates.push((device, family));
+        }
+    }
+
+    let selected = match &selector {
+        DeviceSelector::Auto => None,
+        selector => {
+            let selected = candidates.iter().find(|(p, _)| selector.matches(p)).copied();
+            if selected.is_none() {
+                warn!("No usable device matches {:?}, choosing automatically", selector);
+            }
+            selected
+        }
+    };
+
+    let (physical, family) = selected
+        .or_else(|| candidates.iter().min_by_key(|(p, _)| device_rank(p)).copied())
+        .ok_or(RendererError::NoSuitableDevice)?;
+
+    if physical.properties().device_type == PhysicalDeviceType::Cpu {
+        warn!(
+            "Only a software renderer is available ({}), expect poor performance",
+            physical.properties().device_name
+        );
+    } else {
+        info!("Using device #{}: {}", physical.index(), physical.properties().device_name);
+    }
+
+    Ok((physical, family))
 }
 
 pub fn create_swapchain(
     device: Arc<Device>,
     surface: Arc<Surface<WindowHandle>>,
     format: Format,
-) -> SwapchainCreateOutput {
+    vsync: bool,
+) -> Result<SwapchainCreateOutput, RendererError> {
     let caps = device
         .physical_device()
-        .surface_capabilities(&surface, Default::default())
-        .unwrap();
+        .surface_capabilities(&surface, Default::default())?;
 
     let image_format = Some(format);
 
+    // Fifo is the only mode that must be supported, mailbox is preferred for not tearing
+    let present_mode = if vsync {
+        PresentMode::Fifo
+    } else {
+        let modes = device
+            .physical_device()
+            .surface_present_modes(&surface)?
+            .collect::<Vec<_>>();
+
+        [PresentMode::Mailbox, PresentMode::Immediate]
+            .into_iter()
+            .find(|mode| modes.contains(mode))
+            .unwrap_or(PresentMode::Fifo)
+    };
+
     let (swapchain, images) = Swapchain::new(
         device,
         surface.clone(),
@@ -88,18 +252,17 @@ pub fn create_swapchain(
             },
             composite_alpha: caps.supported_composite_alpha.iter().next().unwrap(),
             image_format,
+            present_mode,
             ..Default::default()
         },
-    )
-    .unwrap();
+    )?;
 
     let swapchain_images = images
         .into_iter()
         .map(ImageView::new_default)
-        .collect::<Result<Vec<_>, _>>()
-        .unwrap();
+        .collect::<Result<Vec<_>, _>>()?;
 
-    (swapchain, swapchain_images)
+    Ok((swapchain, swapchain_images))
 }
 
 pub fn create_viewport(dimensions: [u32; 2]) -> Viewport {
@@ -110,33 +273,41 @@ pub fn create_viewport(dimensions: [u32; 2]) -> Viewport {
     }
 }
 
-pub fn create_framebuffers(
-    render_pass: Arc<RenderPass>,
+fn create_multisampled_attachments(
     device: Arc<Device>,
-    swapchain_images: &[Arc<ImageView<SwapchainImage<WindowHandle>>>],
-) -> FramebufferCreateOutput {
-    let dimensions = swapchain_images[0].dimensions().width_height();
+    dimensions: [u32; 2],
+    format: Format,
+) -> Result<AttachmentViews, RendererError> {
     let depth_view = ImageView::new_default(
         AttachmentImage::transient_multisampled(
             device.clone(),
             dimensions,
             SampleCount::Sample4,
             Format::D32_SFLOAT,
-        )
-        .unwrap(),
-    )
-    .unwrap();
+        )?,
+    )?;
 
-    let color_view = ImageView::new_default(
-        AttachmentImage::transient_multisampled(
-            device,
-            dimensions,
-            SampleCount::Sample4,
-            swapchain_images[0].format().unwrap(),
-        )
-        .unwrap(),
-    )
-    .unwrap();
+    let color_view = ImageView::new_default(AttachmentImage::transient_multisampled(
+        device,
+        dimensions,
+        SampleCount::Sample4,
+        format,
+    )?)?;
+
+    Ok((color_view, depth_view))
+}
+
+pub fn create_framebuffers(
+    render_pass: Arc<RenderPass>,
+    device: Arc<Device>,
+    swapchain_images: &[Arc<ImageView<SwapchainImage<WindowHandle>>>],
+) -> Result<FramebufferCreateOutput, RendererError> {
+    let dimensions = swapchain_images[0].dimensions().width_height();
+    let (color_view, depth_view) = create_multisampled_attachments(
+        device,
+        dimensions,
+        swapchain_images[0].format().unwrap(),
+    )?;
 
     let framebuffers = swapchain_images
         .iter()
@@ -149,35 +320,160 @@ pub fn create_framebuffers(
                 },
             )
         })
-        .collect::<Result<_, _>>()
-        .unwrap();
+        .collect::<Result<_, _>>()?;
+
+    Ok((framebuffers, color_view, depth_view))
+}
+
+pub fn create_offscreen_target(
+    render_pass: Arc<RenderPass>,
+    device: Arc<Device>,
+    dimensions: [u32; 2],
+    format: Format,
+) -> Result<OffscreenTarget, RendererError> {
+    let view = ImageView::new_default(
+        AttachmentImage::with_usage(
+            device.clone(),
+            dimensions,
+            format,
+            ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+        )?,
+    )?;
+    let (color_view, depth_view) = create_multisampled_attachments(device, dimensions, format)?;
+
+    let framebuffer = Framebuffer::new(
+        render_pass,
+        FramebufferCreateInfo {
+            attachments: vec![view.clone(), color_view, depth_view],
+            ..Default::default()
+        },
+    )?;
 
-    (framebuffers, color_view, depth_view)
+    Ok(OffscreenTarget {
+        framebuffer,
+        view,
+        dimensions,
+    })
+}
+
+// Every mesh attribute gets its own binding in layout order, followed by one per-instance
+// binding with constants for the attributes the mesh doesn't provide
+pub fn create_vertex_input_state(layout: &VertexLayout) -> VertexInputState {
+    let mut state = VertexInputState::new();
+
+    for (binding, attribute) in layout.attributes().iter().enumerate() {
+        state = state
+            .binding(
+                binding as u32,
+                VertexInputBindingDescription {
+                    stride: attribute.format.block_size().unwrap() as u32,
+                    input_rate: VertexInputRate::Vertex,
+                },
+            )
+            .attribute(
+                attribute.location,
+                VertexInputAttributeDescription {
+                    binding: binding as u32,
+                    format: attribute.format,
+                    offset: 0,
+                },
+            );
+    }
+
+    let defaults_binding = layout.attributes().len() as u32;
+    state = state.binding(
+        defaults_binding,
+        VertexInputBindingDescription {
+            stride: std::mem::size_of_val(&DEFAULT_ATTRIBUTES.map(|(_, _, value)| value)) as u32,
+            input_rate: VertexInputRate::Instance { divisor: 1 },
+        },
+    );
+
+    for (index, &(location, format, _)) in DEFAULT_ATTRIBUTES.iter().enumerate() {
+        if !layout.contains(location) {
+            state = state.attribute(
+                location,
+                VertexInputAttributeDescription {
+                    binding: defaults_binding,
+                    format,
+                    offset: (index * std::mem::size_of::<[f32; 4]>()) as u32,
+                },
+            );
+        }
+    }
+
+    state
+}
+
+pub const fn convert_topology(topology: MeshTopology) -> PrimitiveTopology {
+    match topology {
+        MeshTopology::PointList => PrimitiveTopology::PointList,
+        MeshTopology::LineList => PrimitiveTopology::LineList,
+        MeshTopology::LineStrip => PrimitiveTopology::LineStrip,
+        MeshTopology::TriangleList => PrimitiveTopology::TriangleList,
+        MeshTopology::TriangleStrip => PrimitiveTopology::TriangleStrip,
+    }
+}
+
+pub const fn is_line_topology(topology: MeshTopology) -> bool {
+    matches!(topology, MeshTopology::LineList | MeshTopology::LineStrip)
 }
 
 pub fn create_pipeline(
     render_pass: Arc<RenderPass>,
     vs: Arc<ShaderModule>,
     fs: Arc<ShaderModule>,
-    viewport: Viewport,
+    key: &PipelineKey,
+    cache: Arc<PipelineCache>,
     device: Arc<Device>,
-) -> Arc<GraphicsPipeline> {
-    let pipeline = GraphicsPipeline::start()
+) -> Result<Arc<GraphicsPipeline>, GraphicsPipelineCreationError> {
+    let rasterization_state = if is_line_topology(key.topology) {
+        RasterizationState {
+            line_width: StateMode::Dynamic,
+            ..RasterizationState::new()
+        }
+    } else {
+        RasterizationState::new()
+    };
+
+    // Blended geometry is drawn back to front after everything else and must not occlude it
+    let (depth_stencil_state, color_blend_state) = if key.blend {
+        (
+            DepthStencilState {
+                depth: Some(DepthState {
+                    enable_dynamic: false,
+                    write_enable: StateMode::Fixed(false),
+                    compare_op: StateMode::Fixed(CompareOp::Less),
+                }),
+                ..DepthStencilState::disabled()
+            },
+            ColorBlendState::new(1).blend_alpha(),
+        )
+    } else {
+        (
+            DepthStencilState::simple_depth_test(),
+            ColorBlendState::new(1),
+        )
+    };
+
+    GraphicsPipeline::start()
         .render_pass(Subpass::from(render_pass, 0).unwrap())
-        .vertex_input_state(BuffersDefinition::new().vertex::<Vertex>())
-        .input_assembly_state(InputAssemblyState::new())
+        .vertex_input_state(create_vertex_input_state(&key.layout))
+        .input_assembly_state(InputAssemblyState::new().topology(convert_topology(key.topology)))
+        .rasterization_state(rasterization_state)
         .multisample_state(MultisampleState {
             rasterization_samples: SampleCount::Sample4,
             ..Default::default()
         })
         .vertex_shader(vs.entry_point("main").unwrap(), ())
         .fragment_shader(fs.entry_point("main").unwrap(), ())
-        .viewport_state(ViewportState::viewport_fixed_scissor_irrelevant(Some(
-            viewport,
-        )))
-        .depth_stencil_state(DepthStencilState::simple_depth_test())
+        .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+        .depth_stencil_state(depth_stencil_state)
+        .color_blend_state(color_blend_state)
+        .build_with_cache(cache)
         .build(device)
-        .unwrap();
-
-    pipeline
 }
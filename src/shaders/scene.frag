@@ -4,6 +4,8 @@ layout(location = 0) out vec4 f_color;
 
 layout(location = 0) in vec3 m_normal_ws;
 layout(location = 1) in vec2 m_tex_coords;
+layout(location = 2) in vec4 m_color;
+layout(location = 3) in vec3 m_position_ws;
 
 layout(set = 0, binding = 0) uniform ViewProjection_Data {
     mat4 view;
@@ -13,27 +15,43 @@ layout(set = 0, binding = 0) uniform ViewProjection_Data {
 
 layout(set = 1, binding = 0) uniform Material_Data {
     vec4 k_diffuse;
+    vec3 k_specular;
+    float shininess;
+    float alpha_cutoff;
 } u_material;
 layout(set = 1, binding = 1) uniform sampler2D u_diffuse_map;
 
 const vec3 light_direction = normalize(vec3(-1, -1, -1));
 
 void main() {
-    vec3 m_camera_direction = normalize(-u_vp.camera_position);
+    vec3 m_camera_direction = normalize(u_vp.camera_position - m_position_ws);
 
-    vec3 k_diffuse = u_material.k_diffuse.rgb;
-    float alpha = u_material.k_diffuse.a;
+    vec3 k_diffuse = u_material.k_diffuse.rgb * m_color.rgb;
+    float alpha = u_material.k_diffuse.a * m_color.a;
 
-    k_diffuse *= texture(u_diffuse_map, m_tex_coords).rgb;
+    vec4 diffuse_sample = texture(u_diffuse_map, m_tex_coords);
+    k_diffuse *= diffuse_sample.rgb;
+    alpha *= diffuse_sample.a;
 
-    vec3 m_light_reflection_ws = reflect(light_direction, m_normal_ws);
+    if (alpha < u_material.alpha_cutoff) {
+        discard;
+    }
 
-    float cos_theta = clamp(dot(m_normal_ws, -light_direction), 0, 1);
+    // Lines and points usually come without normals, draw them unlit
+    if (dot(m_normal_ws, m_normal_ws) < 1e-8) {
+        f_color = vec4(clamp(k_diffuse, 0, 1), alpha);
+        return;
+    }
+
+    vec3 normal = normalize(m_normal_ws);
+    vec3 m_light_reflection_ws = reflect(light_direction, normal);
+
+    float cos_theta = clamp(dot(normal, -light_direction), 0, 1);
     float cos_alpha = clamp(dot(m_camera_direction, m_light_reflection_ws), 0, 1);
 
     vec3 c_diffuse = k_diffuse * cos_theta;
     vec3 c_ambient = k_diffuse * 0.1;
-    vec3 c_specular = k_diffuse * pow(cos_alpha, 5) * 0.0;
+    vec3 c_specular = u_material.k_specular * pow(cos_alpha, max(u_material.shininess, 1.0));
 
     f_color = vec4(clamp(c_diffuse + c_ambient + c_specular, 0, 1), alpha);
 }
@@ -3,6 +3,7 @@
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 tex_coords;
 layout(location = 2) in vec3 normal;
+layout(location = 3) in vec4 color;
 
 layout(set = 0, binding = 0) uniform ViewProjection_Data {
     mat4 view;
@@ -12,15 +13,21 @@ layout(set = 0, binding = 0) uniform ViewProjection_Data {
 
 layout(set = 2, binding = 0) uniform Model_Data {
     mat4 model;
+    float point_size;
 } u_model;
 
 layout(location = 0) out vec3 m_normal_ws;
 layout(location = 1) out vec2 m_tex_coords;
+layout(location = 2) out vec4 m_color;
+layout(location = 3) out vec3 m_position_ws;
 
 void main() {
-    vec4 pos = vec4(position, 1.0);
-    gl_Position = u_vp.projection * u_vp.view * u_model.model * pos;
+    vec4 pos = u_model.model * vec4(position, 1.0);
+    gl_Position = u_vp.projection * u_vp.view * pos;
+    gl_PointSize = u_model.point_size;
 
     m_normal_ws = (u_model.model * vec4(normal, 0.0)).xyz;
     m_tex_coords = tex_coords;
+    m_color = color;
+    m_position_ws = pos.xyz;
 }